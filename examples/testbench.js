@@ -14,7 +14,189 @@ function field(data, i) {
     return data[1][i]
 }
 
-function halt(x) {
+function halt(...result) {
+    if (result.length == 0) {
+        runtime_error("pattern match failed")
+    }
     const util = require('util')
-    console.log(util.inspect(x, { depth: null }))
-}
\ No newline at end of file
+    const x = result[0]
+    if (typeof x == "bigint" || typeof x == "string") {
+        console.log(x.toString())
+    } else {
+        console.log(util.inspect(x, { depth: null }))
+    }
+}
+
+function runtime_error(message, loc) {
+    if (loc === undefined) {
+        console.log(`Runtime error: ${message}`)
+    } else {
+        console.log(`Runtime error at ${loc}: ${message}`)
+    }
+    process.exit(1)
+}
+
+// Ints are numbers while they are exact, and bigints past that
+function int(r) {
+    return Number.isSafeInteger(Number(r)) ? Number(r) : r
+}
+
+function add(x, y) {
+    if (typeof x == "number" && typeof y == "number" && Number.isSafeInteger(x + y)) {
+        return x + y
+    }
+    return int(BigInt(x) + BigInt(y))
+}
+
+function sub(x, y) {
+    if (typeof x == "number" && typeof y == "number" && Number.isSafeInteger(x - y)) {
+        return x - y
+    }
+    return int(BigInt(x) - BigInt(y))
+}
+
+function mul(x, y) {
+    if (typeof x == "number" && typeof y == "number" && Number.isSafeInteger(x * y)) {
+        return x * y
+    }
+    return int(BigInt(x) * BigInt(y))
+}
+
+function div(x, y, loc) {
+    if (y == 0) {
+        runtime_error("division by zero", loc)
+    }
+    return int(BigInt(x) / BigInt(y))
+}
+
+function rem(x, y, loc) {
+    if (y == 0) {
+        runtime_error("division by zero", loc)
+    }
+    return int(BigInt(x) % BigInt(y))
+}
+
+function shift_amount(k, loc) {
+    if (k < 0 || k > 65535) {
+        runtime_error("shift out of range", loc)
+    }
+    return 2n ** BigInt(k)
+}
+
+function shl(x, k, loc) {
+    return int(BigInt(x) * shift_amount(k, loc))
+}
+
+function shr(x, k, loc) {
+    return int(BigInt(x) / shift_amount(k, loc))
+}
+
+function fadd(x, y) {
+    return x + y
+}
+
+function fsub(x, y) {
+    return x - y
+}
+
+function fmul(x, y) {
+    return x * y
+}
+
+function fdiv(x, y) {
+    return x / y
+}
+
+function feq(x, y) {
+    return x == y
+}
+
+function fneq(x, y) {
+    return x != y
+}
+
+function flt(x, y) {
+    return x < y
+}
+
+function fgt(x, y) {
+    return x > y
+}
+
+function fle(x, y) {
+    return x <= y
+}
+
+function fge(x, y) {
+    return x >= y
+}
+
+function to_float(x) {
+    return Number(x)
+}
+
+function truncate(x, loc) {
+    if (!Number.isFinite(x)) {
+        runtime_error("float is not finite", loc)
+    }
+    return int(BigInt(Math.trunc(x)))
+}
+
+// Strings are indexed by code point, and chars are their code points
+function index(s, i, loc) {
+    if (typeof i != "number" || i < 0 || i > s.length) {
+        runtime_error("index out of bounds", loc)
+    }
+    return i
+}
+
+function concat(x, y) {
+    return x + y
+}
+
+function length(s) {
+    return [...s].length
+}
+
+function char_at(s, i, loc) {
+    const chars = [...s]
+    if (index(chars, i, loc) == chars.length) {
+        runtime_error("index out of bounds", loc)
+    }
+    return chars[i].codePointAt(0)
+}
+
+function substring(s, start, end, loc) {
+    const chars = [...s]
+    if (index(chars, start, loc) > index(chars, end, loc)) {
+        runtime_error("index out of bounds", loc)
+    }
+    return chars.slice(start, end).join("")
+}
+
+function compare(x, y) {
+    const xs = [...x].map(c => c.codePointAt(0))
+    const ys = [...y].map(c => c.codePointAt(0))
+    for (let i = 0; i < xs.length && i < ys.length; i++) {
+        if (xs[i] != ys[i]) {
+            return xs[i] < ys[i] ? -1 : 1
+        }
+    }
+    return Math.sign(xs.length - ys.length)
+}
+
+function int_to_string(x) {
+    return x.toString()
+}
+
+function string_to_int(s, loc) {
+    if (!/^-?[0-9]+$/.test(s)) {
+        runtime_error("not an integer", loc)
+    }
+    return int(BigInt(s))
+}
+
+function print(s) {
+    console.log(s)
+    return 0
+}
@@ -1,5 +1,7 @@
 use std::collections::HashMap;
 
+use crate::integer::Integer;
+
 #[derive(Debug, Eq, PartialEq, Clone, Hash)]
 pub struct Name(pub String);
 
@@ -9,11 +11,25 @@ impl Name {
     }
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Assoc {
+    Left,
+    Right,
+}
+
+// How an infix operator groups: higher precedences (0 to 9) bind tighter
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Fixity {
+    pub prec: i32,
+    pub assoc: Assoc,
+}
+
 pub trait Op {
     fn valid(&self) -> bool;
-    fn prec(&self) -> i32;
-    fn assoc(&self) -> i32;
+    fn fixity(&self) -> Option<Fixity>;
     fn unary(&self) -> bool;
+    fn symbolic(&self) -> bool;
+    fn builtin(&self) -> bool;
 }
 
 impl Op for Name {
@@ -23,48 +39,98 @@ impl Op for Name {
             "==" | "!=" | "<" | ">" | "<=" | ">=" => true,
             "&&" | "||" => true,
             "!" => true,
+            // float arithmetic and comparison, which the checker picks for
+            // operators used on floats
+            "+." | "-." | "*." | "/." => true,
+            "==." | "!=." | "<." | ">." | "<=." | ">=." => true,
             _ => false,
         }
     }
 
-    fn prec(&self) -> i32 {
-        match self.0.as_str() {
-            "+" | "-" => 1,
-            "*" | "/" | "%" => 2,
-            "==" | "!=" | "<" | ">" | "<=" | ">=" => 0,
-            "&&" | "||" => 0,
-            "!" => 0,
-            _ => 0,
-        }
+    // The built-in infix operators; user-declared ones have their fixity in
+    // the parser
+    fn fixity(&self) -> Option<Fixity> {
+        let (prec, assoc) = match self.0.trim_end_matches('.') {
+            "||" => (2, Assoc::Right),
+            "&&" => (3, Assoc::Right),
+            "==" | "!=" | "<" | ">" | "<=" | ">=" => (4, Assoc::Left),
+            "+" | "-" => (6, Assoc::Left),
+            "*" | "/" | "%" => (7, Assoc::Left),
+            _ => return None,
+        };
+        Some(Fixity { prec, assoc })
     }
 
-    fn assoc(&self) -> i32 {
-        match self.0.as_str() {
-            "+" | "-" | "*" | "/" | "%" => 1,
-            _ => 1,
-        }
+    fn unary(&self) -> bool {
+        matches!(self.0.as_str(), "-" | "~" | "!")
     }
 
-    fn unary(&self) -> bool {
-        match self.0.as_str() {
-            // "-" | "~" => true,
-            "~" => true,
-            "!" => true,
-            _ => false,
+    // made of operator characters, rather than a name
+    fn symbolic(&self) -> bool {
+        !self.0.starts_with(|c: char| c.is_alphanumeric() || c == '_')
+    }
+
+    // Functions the runtime provides, called like any other
+    fn builtin(&self) -> bool {
+        matches!(
+            self.0.as_str(),
+            "to_float"
+                | "truncate"
+                | "concat"
+                | "length"
+                | "char_at"
+                | "substring"
+                | "compare"
+                | "int_to_string"
+                | "string_to_int"
+                | "print"
+        )
+    }
+}
+
+// The float version of an arithmetic or comparison operator
+pub fn float_op(op: &Name) -> Option<Name> {
+    match op.0.as_str() {
+        "+" | "-" | "*" | "/" | "==" | "!=" | "<" | ">" | "<=" | ">=" => {
+            Some(Name(format!("{}.", op)))
         }
+        _ => None,
     }
 }
 
+// Position in the source text, reported with runtime errors
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub struct Loc {
+    pub line: usize,
+    pub col: usize,
+}
+
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub enum Type {
     Int,
+    Float,
+    Str,
+    Char,
     Bool,
     Unit,
     Fn(Vec<Type>, Box<Type>),
+    Tuple(Vec<Type>),
     UserDef(Name),
+    // a type alias, with the type it names
+    Alias(Name, Box<Type>),
     TyVar(usize), // unresolved type variable
 }
 
+impl Type {
+    // the type an alias names, through any number of aliases
+    pub fn unalias(&self) -> &Type {
+        match self {
+            Type::Alias(_, ty) => ty.unalias(),
+            ty => ty,
+        }
+    }
+}
+
 pub fn fresh_tv() -> Type {
     static mut TVAR_COUNTER: usize = 0;
     unsafe {
@@ -89,16 +155,33 @@ pub struct TypeDef {
 #[derive(Debug, Clone)]
 pub struct Cons {
     pub args: Vec<Type>,
+    // names of the args of a record constructor, empty for positional ones
+    pub fields: Vec<Name>,
+}
+
+impl DataDef {
+    // the constructor of a record type, which has only the one
+    pub fn record(&self) -> Option<(&Name, &Cons)> {
+        match self.cons.len() {
+            1 => self.cons.iter().next().filter(|(_, cons)| !cons.fields.is_empty()),
+            _ => None,
+        }
+    }
 }
 
 #[derive(Debug, Clone)]
 pub enum Pattern {
     Var(Name, Type),
-    Int(i64),
+    Int(Integer),
+    Char(char),
     Bool(bool),
     Data(DataDef, Name, Vec<Pattern>),
-
-    // TODO: structural matching
+    Tuple(Vec<Pattern>),
+    Wildcard,
+    // alternatives, which all bind the same names
+    Or(Vec<Pattern>),
+    // binds the whole value as well as what the pattern binds
+    As(Name, Type, Box<Pattern>),
 }
 
 impl Pattern {
@@ -107,31 +190,219 @@ impl Pattern {
         match self {
             Var(name, ty) => vec![(name.clone(), ty.clone())],
             Int(_) => vec![],
+            Char(_) => vec![],
             Bool(_) => vec![],
-            Data(_, _, pats) => pats.iter().flat_map(|pat| pat.bindings()).collect(),
+            Data(_, _, pats) | Tuple(pats) => pats.iter().flat_map(|pat| pat.bindings()).collect(),
+            Wildcard => vec![],
+            Or(alts) => alts[0].bindings(),
+            As(name, ty, pat) => {
+                let mut bindings = vec![(name.clone(), ty.clone())];
+                bindings.extend(pat.bindings());
+                bindings
+            }
         }
     }
+
+    // Whether some value of the right type fails to match
+    pub fn refutable(&self) -> bool {
+        let row = Row {
+            pats: vec![self.clone()],
+            arm: Name::new("arm"),
+            guard: None,
+            vars: vec![],
+            bound: HashMap::new(),
+        };
+        !exhaustive(vec![row])
+    }
+}
+
+// A row of the pattern matrix: the patterns left to test against the current
+// occurrences, the arm continuation it selects (once its guard holds), and
+// what the arm's variables are bound to so far
+#[derive(Clone)]
+pub struct Row {
+    pub pats: Vec<Pattern>,
+    pub arm: Name,
+    pub guard: Option<Simp>,
+    pub vars: Vec<Name>,
+    pub bound: HashMap<Name, Name>,
+}
+
+// What a refutable pattern tests for; booleans are tested as 0 and 1, and
+// chars as their code points. A tuple can't fail, but is taken apart the way
+// a constructor is.
+#[derive(PartialEq)]
+pub enum Head {
+    Cons(Name, usize),
+    Tuple(usize),
+    Int(Integer),
+}
+
+pub fn head(pat: &Pattern) -> Option<Head> {
+    match pat {
+        Pattern::Var(..) | Pattern::Wildcard => None,
+        Pattern::Int(n) => Some(Head::Int(n.clone())),
+        Pattern::Bool(b) => Some(Head::Int((*b as i64).into())),
+        Pattern::Char(c) => Some(Head::Int((*c as i64).into())),
+        Pattern::Data(_, name, pats) => Some(Head::Cons(name.clone(), pats.len())),
+        Pattern::Tuple(pats) => Some(Head::Tuple(pats.len())),
+        Pattern::Or(..) | Pattern::As(..) => unreachable!("rows are expanded before tests"),
+    }
+}
+
+// The rows that test the same as row, without or-patterns and as-patterns:
+// one row for each alternative, with the as-names bound to their occurrence
+pub fn expand_row(mut row: Row, occs: &[Name]) -> Vec<Row> {
+    let col = row
+        .pats
+        .iter()
+        .position(|pat| matches!(pat, Pattern::Or(..) | Pattern::As(..)));
+
+    let Some(col) = col else {
+        return vec![row];
+    };
+
+    match row.pats[col].clone() {
+        Pattern::As(name, _, pat) => {
+            row.bound.insert(name, occs[col].clone());
+            row.pats[col] = *pat;
+            expand_row(row, occs)
+        }
+        Pattern::Or(alts) => alts
+            .into_iter()
+            .flat_map(|alt| {
+                let mut row = row.clone();
+                row.pats[col] = alt;
+                expand_row(row, occs)
+            })
+            .collect(),
+        _ => unreachable!(),
+    }
+}
+
+// The row as seen by the branch where the value at col has the given head
+// (or by the default branch, for None), if the row can still match there
+pub fn specialize(row: &Row, col: usize, occ: &Name, with: Option<&Head>) -> Option<Row> {
+    let mut pats = row.pats.clone();
+    let mut bound = row.bound.clone();
+
+    let pat = pats.remove(col);
+    let same = with.is_some() && head(&pat).as_ref() == with;
+    let fields = match pat {
+        Pattern::Var(..) | Pattern::Wildcard => {
+            if let Pattern::Var(name, _) = pat {
+                bound.insert(name, occ.clone());
+            }
+            let arity = match with {
+                Some(Head::Cons(_, arity) | Head::Tuple(arity)) => *arity,
+                _ => 0,
+            };
+            vec![Pattern::Wildcard; arity]
+        }
+        Pattern::Data(_, _, fields) | Pattern::Tuple(fields) if same => fields,
+        _ if same => vec![],
+        _ => return None,
+    };
+
+    pats.splice(col..col, fields);
+    Some(Row {
+        pats,
+        arm: row.arm.clone(),
+        guard: row.guard.clone(),
+        vars: row.vars.clone(),
+        bound,
+    })
+}
+
+// Whether the heads found in col cover every value the column can hold
+pub fn complete(heads: &[Head], rows: &[Row], col: usize) -> bool {
+    match &heads[0] {
+        Head::Cons(..) => {
+            let data_def = rows.iter().find_map(|row| match &row.pats[col] {
+                Pattern::Data(data_def, _, _) => Some(data_def),
+                _ => None,
+            });
+            data_def.unwrap().cons.len() == heads.len()
+        }
+        Head::Tuple(_) => true,
+        Head::Int(_) => {
+            let bools = rows
+                .iter()
+                .any(|row| matches!(row.pats[col], Pattern::Bool(_)));
+            bools && heads.len() == 2
+        }
+    }
+}
+
+// Whether every value is matched by some row, found by taking the rows apart
+// the way the match compiler does and looking for a branch where none is left
+fn exhaustive(rows: Vec<Row>) -> bool {
+    // nothing is bound, so the occurrences can all be the same
+    let occ = Name::new("occ");
+    let rows = rows
+        .into_iter()
+        .flat_map(|row| {
+            let occs = vec![occ.clone(); row.pats.len()];
+            expand_row(row, &occs)
+        })
+        .collect::<Vec<_>>();
+
+    if rows.is_empty() {
+        return false;
+    }
+    let Some(col) = rows[0].pats.iter().position(|pat| head(pat).is_some()) else {
+        return true;
+    };
+
+    let mut heads: Vec<Head> = vec![];
+    for row in &rows {
+        if let Some(head) = head(&row.pats[col]) {
+            if !heads.contains(&head) {
+                heads.push(head);
+            }
+        }
+    }
+
+    let specialized = |with| {
+        rows.iter()
+            .filter_map(|row| specialize(row, col, &occ, with))
+            .collect()
+    };
+    (complete(&heads, &rows, col) || exhaustive(specialized(None)))
+        && heads.iter().all(|head| exhaustive(specialized(Some(head))))
 }
 
 #[derive(Debug, Clone)]
 pub enum Expr {
     Bind(Pattern, Simp, Box<Expr>),
     FnDef(FnDef, Box<Expr>),
+    // `e1; e2`, evaluating e1 for its effects
+    Seq(Simp, Loc, Box<Expr>),
     Simp(Simp)
 }
 
 #[derive(Debug, Clone)]
 pub enum Simp {
-    Match(Box<Simp>, Vec<(Pattern, Simp)>),
-    FnCall(Box<Simp>, Vec<Simp>),
+    // arms with an optional guard
+    Match(Box<Simp>, Vec<(Pattern, Option<Simp>, Simp)>),
+    FnCall(Box<Simp>, Vec<Simp>, Loc),
     Block(Box<Expr>),
     Ref(Name),
 
     // literals
-    Int(i64),
+    Int(Integer),
+    Float(f64),
+    Str(String),
+    Char(char),
     Bool(bool),
     Unit,
     Data(Name, Vec<Simp>),
+    Tuple(Vec<Simp>),
+
+    // records; the checker fills in the index of each field, and the
+    // constructor an update builds, once it knows the type
+    Field(Box<Simp>, Name, Option<usize>, Loc),
+    Update(Box<Simp>, Vec<(Name, Simp)>, Option<(Name, Vec<usize>)>, Loc),
 }
 
 #[derive(Debug, Clone)]
@@ -145,6 +416,6 @@ pub struct FnDef {
 #[derive(Debug)]
 pub struct Program {
     pub data_defs: Vec<DataDef>,
-    // pub type_defs: Vec<TypeDef>, // TODO: implement later, parser commented
+    pub type_defs: Vec<TypeDef>,
     pub expr: Option<Expr>,
 }
@@ -1,8 +1,12 @@
 use std::collections::HashMap;
 
 use crate::{
-    ast::{DataDef, Expr, Name, Op, Pattern, Program, Simp},
-    cps::{CntDef, FunDef, LitHigh},
+    ast::{
+        complete, expand_row, head, specialize, DataDef, Expr, FnDef, Head, Name, Op, Pattern,
+        Program, Row, Simp,
+    },
+    cps::{traps, CntDef, FunDef, LitHigh},
+    integer::Integer,
 };
 
 use crate::cps::CpsExpr as BaseCpsExpr;
@@ -16,6 +20,37 @@ type CpsExpr = BaseCpsExpr<LitHigh>;
 type Context = Box<dyn FnOnce(&mut AstToCps, Name) -> CpsExpr>;
 type VecContext = Box<dyn FnOnce(&mut AstToCps, Vec<Name>) -> CpsExpr>;
 
+// source name -> unique name of the binder currently in scope
+type Renaming = HashMap<Name, Name>;
+
+// A name for a constant n, which can't hold its minus sign
+fn const_name(prefix: &str, n: &Integer) -> String {
+    format!("{}{}", prefix, n).replace('-', "m")
+}
+
+// An alternative after the first of an or-pattern, binding the names the
+// first one was renamed to
+fn rename_alt(pat: Pattern, env: &Renaming) -> Pattern {
+    match pat {
+        Pattern::Var(name, ty) => Pattern::Var(env[&name].clone(), ty),
+        Pattern::As(name, ty, pat) => {
+            Pattern::As(env[&name].clone(), ty, Box::new(rename_alt(*pat, env)))
+        }
+        Pattern::Data(data_def, tag, pats) => Pattern::Data(
+            data_def,
+            tag,
+            pats.into_iter().map(|pat| rename_alt(pat, env)).collect(),
+        ),
+        Pattern::Tuple(pats) => {
+            Pattern::Tuple(pats.into_iter().map(|pat| rename_alt(pat, env)).collect())
+        }
+        Pattern::Or(alts) => {
+            Pattern::Or(alts.into_iter().map(|pat| rename_alt(pat, env)).collect())
+        }
+        Pattern::Int(_) | Pattern::Char(_) | Pattern::Bool(_) | Pattern::Wildcard => pat,
+    }
+}
+
 impl AstToCps {
     pub fn convert(program: Program) -> CpsExpr {
         let mut obj = Self {
@@ -23,7 +58,21 @@ impl AstToCps {
             sym_counts: HashMap::new(),
         };
 
-        obj.lower_expr(program.expr.unwrap())
+        let expr = obj.rename_expr(program.expr.unwrap(), &Renaming::new());
+        obj.lower_expr(expr, Box::new(|_, rhs| CpsExpr::Halt(rhs)))
+    }
+
+    fn data_def(&self, cons: &Name) -> &DataDef {
+        self.data_defs
+            .iter()
+            .find(|def| def.cons.contains_key(cons))
+            .unwrap()
+    }
+
+    // the descriptor stored in blocks built with constructor cons
+    fn tag(&self, cons: &Name) -> i64 {
+        let data_def = self.data_def(cons);
+        data_def.cons.iter().position(|(n, _)| n == cons).unwrap() as i64
     }
 
     fn fresh(&mut self, sym: String) -> Name {
@@ -32,6 +81,137 @@ impl AstToCps {
         Name(format!("{}_{}", sym, count))
     }
 
+    // Gives every source binder a unique name before lowering, so no name is
+    // bound twice in the resulting tree. Source names and temporaries share
+    // the counters in `fresh`, which keeps the two from colliding.
+    fn rename_expr(&mut self, expr: Expr, env: &Renaming) -> Expr {
+        match expr {
+            Expr::Bind(pat, rhs, body) => {
+                // let is recursive, the pattern is in scope for the rhs too
+                let mut env = env.clone();
+                let pat = self.rename_pattern(pat, &mut env);
+                let rhs = self.rename_simp(rhs, &env);
+                Expr::Bind(pat, rhs, Box::new(self.rename_expr(*body, &env)))
+            }
+            Expr::FnDef(f, body) => {
+                let mut env = env.clone();
+                let name = self.fresh(f.name.0.clone());
+                env.insert(f.name, name.clone());
+
+                let mut fenv = env.clone();
+                let args = f
+                    .args
+                    .into_iter()
+                    .map(|(arg, ty)| {
+                        let unique = self.fresh(arg.0.clone());
+                        fenv.insert(arg, unique.clone());
+                        (unique, ty)
+                    })
+                    .collect();
+
+                let f = FnDef {
+                    name,
+                    args,
+                    body: Box::new(self.rename_simp(*f.body, &fenv)),
+                    ret: f.ret,
+                };
+                Expr::FnDef(f, Box::new(self.rename_expr(*body, &env)))
+            }
+            Expr::Seq(simp, loc, body) => {
+                let simp = self.rename_simp(simp, env);
+                Expr::Seq(simp, loc, Box::new(self.rename_expr(*body, env)))
+            }
+            Expr::Simp(simp) => Expr::Simp(self.rename_simp(simp, env)),
+        }
+    }
+
+    fn rename_simp(&mut self, simp: Simp, env: &Renaming) -> Simp {
+        use Simp::*;
+        match simp {
+            Match(simp, arms) => {
+                let simp = self.rename_simp(*simp, env);
+                let arms = arms
+                    .into_iter()
+                    .map(|(pat, guard, body)| {
+                        let mut env = env.clone();
+                        let pat = self.rename_pattern(pat, &mut env);
+                        let guard = guard.map(|guard| self.rename_simp(guard, &env));
+                        (pat, guard, self.rename_simp(body, &env))
+                    })
+                    .collect();
+                Match(Box::new(simp), arms)
+            }
+            FnCall(lhs, rhs, loc) => {
+                let lhs = self.rename_simp(*lhs, env);
+                let rhs = rhs
+                    .into_iter()
+                    .map(|arg| self.rename_simp(arg, env))
+                    .collect();
+                FnCall(Box::new(lhs), rhs, loc)
+            }
+            Block(expr) => Block(Box::new(self.rename_expr(*expr, env))),
+            // names bound outside the program (operators) are kept as they are
+            Ref(name) => Ref(env.get(&name).cloned().unwrap_or(name)),
+            Data(name, args) => Data(
+                name,
+                args.into_iter()
+                    .map(|arg| self.rename_simp(arg, env))
+                    .collect(),
+            ),
+            Tuple(args) => Tuple(
+                args.into_iter()
+                    .map(|arg| self.rename_simp(arg, env))
+                    .collect(),
+            ),
+            Int(_) | Float(_) | Str(_) | Char(_) | Bool(_) | Unit => simp,
+            Field(record, field, index, loc) => {
+                Field(Box::new(self.rename_simp(*record, env)), field, index, loc)
+            }
+            Update(record, fields, resolved, loc) => {
+                let record = self.rename_simp(*record, env);
+                let fields = fields
+                    .into_iter()
+                    .map(|(field, value)| (field, self.rename_simp(value, env)))
+                    .collect();
+                Update(Box::new(record), fields, resolved, loc)
+            }
+        }
+    }
+
+    fn rename_pattern(&mut self, pat: Pattern, env: &mut Renaming) -> Pattern {
+        match pat {
+            Pattern::Var(name, ty) => {
+                let unique = self.fresh(name.0.clone());
+                env.insert(name, unique.clone());
+                Pattern::Var(unique, ty)
+            }
+            Pattern::Data(data_def, tag, pats) => {
+                let pats = pats
+                    .into_iter()
+                    .map(|pat| self.rename_pattern(pat, env))
+                    .collect();
+                Pattern::Data(data_def, tag, pats)
+            }
+            Pattern::Tuple(pats) => Pattern::Tuple(
+                pats.into_iter()
+                    .map(|pat| self.rename_pattern(pat, env))
+                    .collect(),
+            ),
+            Pattern::Or(alts) => {
+                let mut alts = alts.into_iter();
+                let first = self.rename_pattern(alts.next().unwrap(), env);
+                let rest = alts.map(|alt| rename_alt(alt, env));
+                Pattern::Or(std::iter::once(first).chain(rest).collect())
+            }
+            Pattern::As(name, ty, pat) => {
+                let unique = self.fresh(name.0.clone());
+                env.insert(name, unique.clone());
+                Pattern::As(unique, ty, Box::new(self.rename_pattern(*pat, env)))
+            }
+            Pattern::Int(_) | Pattern::Char(_) | Pattern::Bool(_) | Pattern::Wildcard => pat,
+        }
+    }
+
     fn simp_list(&mut self, mut simps: Vec<Simp>, ctx: VecContext, mut acc: Vec<Name>) -> CpsExpr {
         if simps.is_empty() {
             ctx(self, acc)
@@ -49,48 +229,32 @@ impl AstToCps {
         }
     }
 
-    fn match_arms(&mut self, val: Name, mut arms: Vec<(Pattern, Simp)>, ret: Name) -> CpsExpr {
+    fn match_arms(
+        &mut self,
+        val: Name,
+        arms: Vec<(Pattern, Option<Simp>, Simp)>,
+        ret: Name,
+    ) -> CpsExpr {
         // match val with arms, then call continuation ret with the result of the expression
-        assert!(arms.len() > 0);
-
-        if arms.len() == 1 {
-            // no match now is invalid
-            let no_match = Name("halt".to_string());
-            let (pat, simp) = arms.remove(0);
-
-            let body = self.lower_simp(
-                simp,
-                Box::new(move |_, simp| CpsExpr::AppC {
-                    cnt: ret.clone(),
-                    args: vec![simp],
-                }),
-            );
-            self.lower_pattern_match(pat, val, body, no_match)
-        } else {
-            let (pat, simp) = arms.remove(0);
+        assert!(!arms.is_empty());
 
-            let no_match = self.fresh("pm_alt".to_string());
-            let no_match_cont = CntDef {
-                name: no_match.clone(),
-                args: vec![],
-                body: self.match_arms(val.clone(), arms, ret.clone()),
-            };
-
-            let body = self.lower_simp(
-                simp,
-                Box::new(move |_, simp| CpsExpr::AppC {
-                    cnt: ret.clone(),
-                    args: vec![simp],
-                }),
-            );
+        let arms = arms
+            .into_iter()
+            .map(|(pat, guard, simp)| {
+                let ret = ret.clone();
+                let body = self.lower_simp(
+                    simp,
+                    Box::new(move |_, simp| CpsExpr::AppC {
+                        cnt: ret,
+                        args: vec![simp],
+                    }),
+                );
+                (pat, guard, body)
+            })
+            .collect();
 
-            CpsExpr::Cnts {
-                cnts: vec![no_match_cont],
-                body: Box::new(self.lower_pattern_match(pat, val, body, no_match)),
-            }
-        }
+        self.match_tree(val, arms)
     }
-
     fn lower_simp(&mut self, simp: Simp, ctx: Context) -> CpsExpr {
         use Simp::*;
         match simp {
@@ -112,16 +276,17 @@ impl AstToCps {
                     }
                 }),
             ),
-            FnCall(lhs, rhs) => {
+            FnCall(lhs, rhs, loc) => {
                 let lhs = *lhs;
 
                 match lhs {
-                    Ref(name) if name.valid() => self.simp_list(
+                    Ref(name) if name.valid() || name.builtin() => self.simp_list(
                         rhs,
-                        Box::new(|s, rhs| {
+                        Box::new(move |s, rhs| {
                             let n_prim = s.fresh("prim".to_string());
                             CpsExpr::Prim {
                                 name: n_prim.clone(),
+                                loc: traps(&name).then_some(loc),
                                 op: name,
                                 args: rhs,
                                 body: Box::new(ctx(s, n_prim)),
@@ -159,71 +324,125 @@ impl AstToCps {
                     }
                 }
             }
+            Block(expr) => self.lower_expr(*expr, ctx),
             Ref(name) => ctx(self, name),
             Int(n) => {
-                let name = self.fresh(format!("c{}", n));
+                let name = self.fresh(const_name("c", &n));
                 CpsExpr::Const {
                     name: name.clone(),
                     value: LitHigh::Int(n),
                     body: Box::new(ctx(self, name)),
                 }
             }
+            Float(x) => {
+                let name = self.fresh("float".to_string());
+                CpsExpr::Const {
+                    name: name.clone(),
+                    value: LitHigh::Float(x),
+                    body: Box::new(ctx(self, name)),
+                }
+            }
+            Str(text) => {
+                let name = self.fresh("str".to_string());
+                CpsExpr::Const {
+                    name: name.clone(),
+                    value: LitHigh::Str(text),
+                    body: Box::new(ctx(self, name)),
+                }
+            }
+            Char(c) => self.lower_simp(Int((c as i64).into()), ctx),
             Bool(b) => {
                 let n = if b { 1 } else { 0 };
-                self.lower_simp(Int(n), ctx)
+                self.lower_simp(Int(n.into()), ctx)
             }
+            Unit => self.lower_simp(Int(0.into()), ctx),
             Data(name, args) => {
-                let data_def = self
-                    .data_defs
-                    .iter()
-                    .find(|def| def.cons.contains_key(&name))
-                    .unwrap();
-
-                let tag = data_def.cons.iter().position(|(n, _)| n == &name).unwrap() as i64;
-                let desc = self.fresh(format!("d{}", tag));
+                let tag = self.tag(&name);
                 let data = self.fresh(format!("data_{}", name));
+                self.alloc(tag, data, args, ctx)
+            }
+            // a block like a data value of a type with just one constructor
+            Tuple(args) => {
+                let tuple = self.fresh("tuple".to_string());
+                self.alloc(0, tuple, args, ctx)
+            }
+            Field(record, _, index, _) => self.lower_simp(
+                *record,
+                Box::new(move |s, record| {
+                    let idx = s.fresh(format!("i{}", index.unwrap()));
+                    let field = s.fresh("field".to_string());
+                    CpsExpr::Const {
+                        name: idx.clone(),
+                        value: LitHigh::Int((index.unwrap() as i64).into()),
+                        body: Box::new(CpsExpr::Prim {
+                            name: field.clone(),
+                            op: Name("field".to_string()),
+                            args: vec![record, idx],
+                            loc: None,
+                            body: Box::new(ctx(s, field)),
+                        }),
+                    }
+                }),
+            ),
+            // a new block with the fields of the record, but for the new values
+            Update(record, fields, resolved, _) => {
+                let (cons, indices) = resolved.unwrap();
+                let tag = self.tag(&cons);
+                let arity = self.data_def(&cons).cons[&cons].args.len();
+                let values = fields.into_iter().map(|(_, value)| value).collect();
 
-                self.simp_list(
-                    args,
-                    Box::new(move |s, args| {
-                        CpsExpr::Const {
-                            name: desc.clone(),
-                            value: LitHigh::Int(tag),
-                            body: Box::new(CpsExpr::Prim {
-                                name: data.clone(),
-                                op: Name("data".to_string()),
-
-                                // args is desc, ...args
-                                args: vec![desc].into_iter().chain(args).collect(),
-                                body: Box::new(ctx(s, data)),
+                self.lower_simp(
+                    *record,
+                    Box::new(move |s, record| {
+                        s.simp_list(
+                            values,
+                            Box::new(move |s, values| {
+                                s.data_fields(
+                                    record,
+                                    arity,
+                                    Box::new(move |s, mut args| {
+                                        for (i, value) in indices.into_iter().zip(values) {
+                                            args[i] = value;
+                                        }
+                                        let data = s.fresh(format!("data_{}", cons));
+                                        s.block(tag, data, args, ctx)
+                                    }),
+                                    vec![],
+                                )
                             }),
-                        }
+                            vec![],
+                        )
                     }),
-                    vec![],
                 )
             }
-            _ => unimplemented!(),
         }
     }
 
-    fn pat_list(
-        &mut self,
-        mut pats: Vec<Pattern>,
-        mut vals: Vec<Name>,
-        body: CpsExpr,
-        no_match: Name,
-    ) -> CpsExpr {
-        assert!(pats.len() > 0);
-        assert!(pats.len() == vals.len());
+    // a block named data holding the descriptor tag and the values of args
+    fn alloc(&mut self, tag: i64, data: Name, args: Vec<Simp>, ctx: Context) -> CpsExpr {
+        self.simp_list(
+            args,
+            Box::new(move |s, args| s.block(tag, data, args, ctx)),
+            vec![],
+        )
+    }
 
-        let pat = pats.remove(0);
-        let val = vals.remove(0);
+    // a block named data holding the descriptor tag and args
+    fn block(&mut self, tag: i64, data: Name, args: Vec<Name>, ctx: Context) -> CpsExpr {
+        let desc = self.fresh(format!("d{}", tag));
 
-        if pats.len() == 0 {
-            self.lower_pattern_match(pat, val, body, no_match)
-        } else {
-            let remaining = self.pat_list(pats, vals, body, no_match.clone());
-            self.lower_pattern_match(pat, val, remaining, no_match)
+        CpsExpr::Const {
+            name: desc.clone(),
+            value: LitHigh::Int(tag.into()),
+            body: Box::new(CpsExpr::Prim {
+                name: data.clone(),
+                op: Name("data".to_string()),
+
+                // args is desc, ...args
+                args: vec![desc].into_iter().chain(args).collect(),
+                loc: None,
+                body: Box::new(ctx(self, data)),
+            }),
         }
     }
 
@@ -243,122 +462,287 @@ impl AstToCps {
 
             CpsExpr::Const {
                 name: idx.clone(),
-                value: LitHigh::Int((acc.len() - 1) as i64),
+                value: LitHigh::Int(((acc.len() - 1) as i64).into()),
                 body: Box::new(CpsExpr::Prim {
                     name: field.clone(),
                     op: Name("field".to_string()),
                     args: vec![data.clone(), idx],
+                    loc: None,
                     body: Box::new(self.data_fields(data, num_fields, ctx, acc)),
                 }),
             }
         }
     }
 
-    fn lower_pattern_match(
-        &mut self,
-        pat: Pattern,
-        val: Name,
-        body: CpsExpr,
-        no_match: Name,
-    ) -> CpsExpr {
-        match pat {
-            Pattern::Var(name, _) => CpsExpr::Prim {
-                name,
-                op: Name("id".to_string()),
-                args: vec![val],
-                body: Box::new(body),
-            },
-            Pattern::Int(n) => {
-                // create two continuations, one for good match, one for bad
-                let good = self.fresh("pm_good".to_string());
-
-                let good_cnt = CntDef {
-                    name: good.clone(),
-                    args: vec![],
-                    body,
-                };
+    // Compiles a match of val against arms into a decision tree, which tests
+    // every sub-value at most once on each path. Each arm body becomes a
+    // continuation taking the arm's pattern variables, jumped to from the
+    // leaves of the tree that select it.
+    fn match_tree(&mut self, val: Name, arms: Vec<(Pattern, Option<Simp>, CpsExpr)>) -> CpsExpr {
+        let mut cnts = vec![];
+        let mut rows = vec![];
 
-                let desc = self.fresh(format!("p{}", n));
-                CpsExpr::Const {
-                    name: desc.clone(),
-                    value: LitHigh::Int(n),
-                    body: Box::new(CpsExpr::Cnts {
-                        cnts: vec![good_cnt],
-                        body: Box::new(CpsExpr::If {
-                            op: Name("==".to_string()),
-                            args: vec![desc, val],
-                            t: good,
-                            f: no_match,
-                        }),
-                    }),
+        for (pat, guard, body) in arms {
+            let arm = self.fresh("arm".to_string());
+            let vars = pat
+                .bindings()
+                .into_iter()
+                .map(|(name, _)| name)
+                .collect::<Vec<_>>();
+
+            cnts.push(CntDef {
+                name: arm.clone(),
+                args: vars.clone(),
+                body,
+            });
+            rows.push(Row {
+                pats: vec![pat],
+                arm,
+                guard,
+                vars,
+                bound: HashMap::new(),
+            });
+        }
+
+        CpsExpr::Cnts {
+            cnts,
+            body: Box::new(self.decide(vec![val], rows)),
+        }
+    }
+
+    fn decide(&mut self, occs: Vec<Name>, rows: Vec<Row>) -> CpsExpr {
+        let mut rows = rows
+            .into_iter()
+            .flat_map(|row| expand_row(row, &occs))
+            .collect::<Vec<_>>();
+
+        if rows.is_empty() {
+            // no arm applies
+            return CpsExpr::AppC {
+                cnt: Name("halt".to_string()),
+                args: vec![],
+            };
+        }
+
+        // test the first column the first row can fail on
+        let Some(col) = rows[0].pats.iter().position(|pat| head(pat).is_some()) else {
+            // the first row matches whatever is left
+            let mut row = rows.remove(0);
+            for (pat, occ) in row.pats.into_iter().zip(&occs) {
+                if let Pattern::Var(name, _) = pat {
+                    row.bound.insert(name, occ.clone());
                 }
             }
-            Pattern::Bool(b) => {
-                // convert bool to int
-                let npat = if b { Pattern::Int(1) } else { Pattern::Int(0) };
-                self.lower_pattern_match(npat, val, body, no_match)
-            }
-            Pattern::Data(data_def, tag, pats) => {
-                let tag_idx = data_def
-                    .cons
-                    .iter()
-                    .position(|(name, _)| name == &tag)
-                    .unwrap();
-
-                let tag = tag_idx as i64;
-
-                let desc = self.fresh(format!("d{}", tag));
-                let good = self.fresh("pm_good".to_string());
-
-                let no_match_2 = no_match.clone();
-                let good_cnt = CntDef {
-                    name: good.clone(),
-                    args: vec![],
-                    // if good, we need to check subpatterns
-                    body: if pats.len() == 0 {
-                        body
-                    } else {
-                        self.data_fields(
-                            val.clone(),
-                            pats.len(),
-                            Box::new(move |s, fields| s.pat_list(pats, fields, body, no_match_2)),
-                            vec![],
-                        )
-                    },
-                };
+            let jump = CpsExpr::AppC {
+                cnt: row.arm.clone(),
+                args: row.vars.iter().map(|var| row.bound[var].clone()).collect(),
+            };
 
-                // otherwise, we directly jump to no_match
-                let val_desc = self.fresh("desc".to_string());
-                CpsExpr::Const {
-                    name: desc.clone(),
-                    value: LitHigh::Int(tag),
-                    body: Box::new(CpsExpr::Prim {
-                        name: val_desc.clone(),
-                        op: Name("desc".to_string()),
-                        args: vec![val.clone()],
+            let Some(guard) = row.guard else {
+                return jump;
+            };
+            // the guard is tested on the first alternative that matches only
+            rows.retain(|other| other.arm != row.arm);
+            return self.guard(guard, &row.bound, jump, occs, rows);
+        };
+
+        self.switch(occs, rows, col)
+    }
+
+    // Tests the guard of the row a leaf selects, on the values its variables
+    // are bound to there. When it fails, the match goes on with the rows
+    // after it, as far as they are left in this branch.
+    fn guard(
+        &mut self,
+        guard: Simp,
+        bound: &HashMap<Name, Name>,
+        jump: CpsExpr,
+        occs: Vec<Name>,
+        rest: Vec<Row>,
+    ) -> CpsExpr {
+        let fail = self.fresh("guard_fail".to_string());
+        let fail_body = self.decide(occs, rest);
+
+        // each leaf has its own copy of the guard, with fresh binders
+        let guard = self.rename_simp(guard, bound);
+        let test = self.lower_simp(
+            guard,
+            Box::new({
+                let fail = fail.clone();
+                move |s, guard| {
+                    let ok = s.fresh("guard_ok".to_string());
+                    let one = s.fresh("c1".to_string());
+                    CpsExpr::Const {
+                        name: one.clone(),
+                        value: LitHigh::Int(1.into()),
                         body: Box::new(CpsExpr::Cnts {
-                            cnts: vec![good_cnt],
+                            cnts: vec![CntDef {
+                                name: ok.clone(),
+                                args: vec![],
+                                body: jump,
+                            }],
                             body: Box::new(CpsExpr::If {
                                 op: Name("==".to_string()),
-                                args: vec![desc, val_desc],
-                                t: good,
-                                f: no_match,
+                                args: vec![one, guard],
+                                t: ok,
+                                f: fail,
                             }),
                         }),
-                    }),
+                    }
+                }
+            }),
+        );
+
+        CpsExpr::Cnts {
+            cnts: vec![CntDef {
+                name: fail,
+                args: vec![],
+                body: fail_body,
+            }],
+            body: Box::new(test),
+        }
+    }
+
+    fn switch(&mut self, occs: Vec<Name>, rows: Vec<Row>, col: usize) -> CpsExpr {
+        let occ = occs[col].clone();
+
+        let mut heads: Vec<Head> = vec![];
+        for row in &rows {
+            if let Some(head) = head(&row.pats[col]) {
+                if !heads.contains(&head) {
+                    heads.push(head);
                 }
             }
-            _ => unimplemented!(),
+        }
+
+        // rows that don't care about this column
+        let default = if complete(&heads, &rows, col) {
+            None
+        } else {
+            let rows = rows
+                .iter()
+                .filter_map(|row| specialize(row, col, &occ, None))
+                .collect();
+            let mut occs = occs.clone();
+            occs.remove(col);
+            Some(self.decide(occs, rows))
+        };
+
+        let mut cases = vec![];
+        for head in heads {
+            let rows = rows
+                .iter()
+                .filter_map(|row| specialize(row, col, &occ, Some(&head)))
+                .collect::<Vec<_>>();
+
+            match head {
+                Head::Int(n) => {
+                    let mut occs = occs.clone();
+                    occs.remove(col);
+                    cases.push((n, self.decide(occs, rows)));
+                }
+                Head::Cons(_, arity) | Head::Tuple(arity) => {
+                    let tag = match &head {
+                        Head::Cons(name, _) => self.tag(name),
+                        _ => 0,
+                    };
+                    let occs = occs.clone();
+                    let branch = self.data_fields(
+                        occ.clone(),
+                        arity,
+                        Box::new(move |s, fields| {
+                            let occs = occs[..col]
+                                .iter()
+                                .cloned()
+                                .chain(fields)
+                                .chain(occs[col + 1..].iter().cloned())
+                                .collect();
+                            s.decide(occs, rows)
+                        }),
+                        vec![],
+                    );
+                    cases.push((tag.into(), branch));
+                }
+            }
+        }
+
+        if matches!(rows[0].pats[col], Pattern::Data(..)) {
+            // read the tag once for all the cases
+            let val_desc = self.fresh("desc".to_string());
+            CpsExpr::Prim {
+                name: val_desc.clone(),
+                op: Name("desc".to_string()),
+                args: vec![occ],
+                loc: None,
+                body: Box::new(self.test_chain(val_desc, cases, default)),
+            }
+        } else {
+            self.test_chain(occ, cases, default)
         }
     }
 
-    fn lower_expr(&mut self, high: Expr) -> CpsExpr {
+    fn test_chain(
+        &mut self,
+        val: Name,
+        mut cases: Vec<(Integer, CpsExpr)>,
+        default: Option<CpsExpr>,
+    ) -> CpsExpr {
+        if cases.is_empty() {
+            return default.unwrap();
+        }
+
+        let (n, branch) = cases.remove(0);
+        if cases.is_empty() && default.is_none() {
+            // every other value was ruled out already
+            return branch;
+        }
+
+        let good = self.fresh("pm_good".to_string());
+        let next = self.fresh("pm_next".to_string());
+        let desc = self.fresh(const_name("p", &n));
+
+        CpsExpr::Const {
+            name: desc.clone(),
+            value: LitHigh::Int(n),
+            body: Box::new(CpsExpr::Cnts {
+                cnts: vec![
+                    CntDef {
+                        name: good.clone(),
+                        args: vec![],
+                        body: branch,
+                    },
+                    CntDef {
+                        name: next.clone(),
+                        args: vec![],
+                        body: self.test_chain(val.clone(), cases, default),
+                    },
+                ],
+                body: Box::new(CpsExpr::If {
+                    op: Name("==".to_string()),
+                    args: vec![desc, val],
+                    t: good,
+                    f: next,
+                }),
+            }),
+        }
+    }
+
+    fn lower_expr(&mut self, high: Expr, ctx: Context) -> CpsExpr {
         match high {
             Expr::Bind(pat, rhs, body) => self.lower_simp(
                 rhs,
                 Box::new(|s: &mut Self, rhs| {
-                    let body = s.lower_expr(*body);
-                    s.lower_pattern_match(pat, rhs, body, Name("halt".to_string()))
+                    let body = s.lower_expr(*body, ctx);
+                    match pat {
+                        Pattern::Var(name, _) => CpsExpr::Prim {
+                            name,
+                            op: Name("id".to_string()),
+                            args: vec![rhs],
+                            loc: None,
+                            body: Box::new(body),
+                        },
+                        _ => s.match_tree(rhs, vec![(pat, None, body)]),
+                    }
                 }),
             ),
             Expr::FnDef(f, body) => {
@@ -380,10 +764,651 @@ impl AstToCps {
 
                 CpsExpr::Funs {
                     funs: vec![lfun],
-                    body: Box::new(self.lower_expr(*body)),
+                    body: Box::new(self.lower_expr(*body, ctx)),
                 }
             }
-            Expr::Simp(simp) => self.lower_simp(simp, Box::new(|_, rhs| CpsExpr::Halt(rhs))),
+            // the value of simp goes unused
+            Expr::Seq(simp, _, body) => {
+                self.lower_simp(simp, Box::new(|s, _| s.lower_expr(*body, ctx)))
+            }
+            Expr::Simp(simp) => self.lower_simp(simp, ctx),
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use logos::Logos;
+
+    use super::*;
+    use crate::{checker::TypeChecker, cps_eval, interp, lexer::Token, parser::Parser};
+    use crate::{pass_manager::PassManager, verify::Verifier};
+
+    fn eval(tree: &CpsExpr) -> String {
+        match cps_eval::eval(tree) {
+            Ok(value) => value.to_string(),
+            Err(err) => err.to_string(),
+        }
+    }
+
+    fn typed(source: &str) -> Program {
+        let program = Parser::new(Token::lexer(source)).parse_program();
+        TypeChecker::new().infer(program)
+    }
+
+    // The results of the tree interpreter and of evaluating the lowered CPS,
+    // which is checked to be well formed and to compute the same once
+    // optimized
+    fn run(source: &str) -> (String, String) {
+        let program = typed(source);
+        let interp = match interp::eval_prog(&program) {
+            Ok(value) => value.to_string(),
+            Err(err) => err.to_string(),
+        };
+
+        let cps = AstToCps::convert(program);
+        if let Err(err) = Verifier::new().unique_binders(true).verify(&cps) {
+            panic!("ill-formed CPS: {}\n{:#}", err, cps);
+        }
+        let optimized = PassManager::opt_level(2).unwrap().run(cps.clone());
+        let result = eval(&cps);
+        assert_eq!(result, eval(&optimized), "optimized:\n{:#}", optimized);
+
+        (interp, result)
+    }
+
+    fn check(source: &str, expected: &str) {
+        let (interp, cps) = run(source);
+        assert_eq!(interp, expected, "tree interpreter");
+        assert_eq!(cps, expected, "CPS evaluator");
+    }
+
+    // Each arm is lowered once, however many branches of the decision tree
+    // lead to it
+    #[test]
+    fn match_arms_are_shared() {
+        let source = "
+            data List = Nil | Cons(Int, List)
+            data Pair = P(List, List)
+            fn f(a, b) = match P(a, b)
+                | P(Nil, l) => 1001
+                | P(l, Nil) => 1002
+                | P(Cons(x, r), Cons(y, s)) => x * 10 + y
+            f(Nil, Nil) + f(Cons(1, Nil), Nil) + f(Cons(1, Nil), Cons(2, Nil))
+        ";
+        check(source, "2015");
+
+        let cps = AstToCps::convert(typed(source)).to_string();
+        assert_eq!(cps.matches("= 1001;").count(), 1);
+        assert_eq!(cps.matches("= 1002;").count(), 1);
+    }
+
+    #[test]
+    fn match_nested_and_literal_patterns() {
+        check(
+            "
+            data List = Nil | Cons(Int, List)
+            fn f(l) = match l
+                | Cons(1, Cons(y, Nil)) => y
+                | Cons(2, r) => 100
+                | Cons(x, Cons(w, Cons(z, t))) => z * 1000
+                | m => 10000
+            fn g(b) = match b
+                | true => 1
+                | false => 2
+            f(Cons(1, Cons(5, Nil))) + f(Cons(2, Nil)) + f(Cons(3, Cons(3, Cons(7, Nil))))
+                + f(Nil) + g(false) * 100000
+            ",
+            "217105",
+        );
+    }
+
+    // both evaluators stop at the operator that fails
+    #[test]
+    fn division_by_zero() {
+        check(
+            "fn f(a, b) = a / b\nf(17, 0)",
+            "Runtime error at 1:16: division by zero",
+        );
+        check("fn f(a, b) = a % b\nf(17, 5) * 10 + f(-17, 5)", "18");
+        check(
+            "fn f(a, b) = a % b\nf(17, 5) +\n  f(17, 0)",
+            "Runtime error at 1:16: division by zero",
+        );
+    }
+
+    // ints grow past a machine word and come back when they fit
+    #[test]
+    fn bignum_promotion() {
+        check(
+            "
+            fn f(a) = a + 1
+            fn pow(b, n) = if n == 0 1 else b * pow(b, n - 1)
+            let big = f(9223372036854775807)
+            let huge = pow(big, 3)
+            huge / big / big - big
+            ",
+            "0",
+        );
+        check(
+            "fn f(a) = a * 4\nf(9223372036854775807)",
+            "36893488147419103228",
+        );
+        check(
+            "fn f(a) = 0 - a - 1\nf(9223372036854775807) - 1",
+            "-9223372036854775809",
+        );
+    }
+
+    // the same operators on floats, which only meet ints through conversions
+    #[test]
+    fn float_arithmetic() {
+        check(
+            "
+            fn area(r) = 3.5 * r * r
+            fn mean(a, b) = (a + b) / 2.0
+            let x = area(2.0) - 0.25
+            let y = mean(1.5e1, to_float(4))
+            if x > y truncate(x * 10.0) + truncate(0.0 - y) else 0
+            ",
+            "128",
+        );
+        check("1.0 / 4.0 + 2.5e-1", "0.5");
+        check("to_float(7) / 2.0", "3.5");
+    }
+
+    // lengths and indices count chars, escapes included
+    #[test]
+    fn strings_and_chars() {
+        check(
+            r#"
+            let s = concat("h\u{e9}llo, ", "w\"rld\n")
+            let c = char_at(s, 1)
+            let t = substring(s, 7, 12)
+            let n = string_to_int(int_to_string(length(s) * 10))
+            let ok = if c == '\u{e9}' compare(t, "w\"rl") > 0 else false
+            if ok n + length(t) else 0
+            "#,
+            "135",
+        );
+        check(r#"concat("a\tb", int_to_string(-3))"#, "a\tb-3");
+        check(r#"if char_at("\\\'", 1) == '\'' 1 else 0"#, "1");
+    }
+
+    #[test]
+    fn string_errors() {
+        check(
+            "\nchar_at(\"abc\", 3)",
+            "Runtime error at 2:1: index out of bounds",
+        );
+        check(
+            "substring(\"abc\", 2, 1)",
+            "Runtime error at 1:1: index out of bounds",
+        );
+        check(
+            "string_to_int(\"12a\")",
+            "Runtime error at 1:1: not an integer",
+        );
+    }
+
+    #[test]
+    fn tuples() {
+        check(
+            "
+            data List = Nil | Cons(Int, List)
+            fn swap(p) = match p
+                | (a, b) => (b, a)
+            fn sum_pairs(l, acc) = match (l, acc)
+                | (Nil, (x, y)) => x * 100 + y
+                | (Cons(h, t), (x, y)) => sum_pairs(t, (x + h, y + 1))
+            let (lo, hi) = swap((7, 3))
+            let ((a, b), c) = ((lo, hi), (1, true))
+            sum_pairs(Cons(a, Cons(b, Nil)), (0, 0)) * 10 + lo
+            ",
+            "10023",
+        );
+    }
+
+    // fields are given by name in any order, by a variable of the same name,
+    // or by position
+    #[test]
+    fn record_construction() {
+        check(
+            r#"
+            data Person = Person { name: String, age: Int }
+            let age = 40
+            let a = Person { age: 3, name: "Al" }
+            let b = Person { age, name: "Bob" }
+            let c = Person("Cy", 7)
+            let Person(n, x) = a
+            let Person(m, y) = b
+            let Person(_, z) = c
+            x * 1000 + y * 10 + z + length(n) * 100000 + length(m) * 1000000
+            "#,
+            "3203407",
+        );
+    }
+
+    #[test]
+    fn record_fields() {
+        check(
+            r#"
+            data Person = Person { name: String, age: Int }
+            data Couple = Couple { a: Person, b: Person }
+            fn age(p) = p.age
+            let c = Couple { a: Person { name: "Al", age: 3 }, b: Person("Bo", 40) }
+            age(c.a) * 100 + c.b.age + length(c.b.name) * 10000
+            "#,
+            "20340",
+        );
+    }
+
+    // an update builds a new record; the old one is unchanged, and the new
+    // values may use it
+    #[test]
+    fn record_update() {
+        check(
+            r#"
+            data Point = { x: Int, y: Int, z: Int }
+            fn shift(p) = { p with z = p.x + 1, x = p.z }
+            let p = Point(1, 2, 3)
+            let q = shift(shift(p))
+            let with_ = { q with y = 9 }
+            p.x * 10000 + q.x * 1000 + q.z * 100 + with_.y * 10 + q.y
+            "#,
+            "12492",
+        );
+    }
+
+    #[test]
+    fn record_patterns() {
+        check(
+            r#"
+            data Shape = Circle { r: Int } | Rect { w: Int, h: Int }
+            fn area(s) = match s
+                | Circle { r } => 3 * r * r
+                | Rect { w, h: 2 } => w * 2
+                | Rect { h, w } => w * h
+            let Rect { w: width } = Rect { h: 5, w: 4 } else { 0 }
+            area(Circle { r: 2 }) * 100 + area(Rect { h: 2, w: 7 }) + area(Rect { w: 3, h: 3 }) + width
+            "#,
+            "1227",
+        );
+    }
+
+    #[test]
+    fn type_aliases() {
+        check(
+            "
+            type Pos = (Int, Int)
+            type Path = List
+            data List = Nil | Cons(Pos, Path)
+            fn len(p: Path): Int = match p
+                | Nil => 0
+                | Cons((x, y), rest) => x + y + len(rest)
+            len(Cons((1, 2), Cons((3, 4), Nil)))
+            ",
+            "10",
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "type alias A refers to itself, through A -> B -> List -> A")]
+    fn type_alias_cycle() {
+        typed("type A = B\ntype B = List\ntype List = A\n1");
+    }
+
+    #[test]
+    #[should_panic(expected = "cannot unify Pos and Bool")]
+    fn type_alias_in_errors() {
+        typed("type Pos = (Int, Int)\nfn f(p: Pos): Int = match p\n    | (x, y) => x + y\nf(true)");
+    }
+
+    #[test]
+    fn match_wildcard_or_and_as_patterns() {
+        check(
+            "
+            data List = Cons(Int, List) | Nil
+
+            fn classify(n: Int): Int = match n
+                | 0 | 1 => 1
+                | x if x < 10 => 2
+                | _ => 3
+
+            fn sum(l: List): Int = match l
+                | Nil => 0
+                | xs @ Cons(x, rest) if x > 100 => sum(rest)
+                | Cons(x, rest) => x + sum(rest)
+
+            fn firsts(l: List): Int = match l
+                | Cons(x, Cons(y, _)) | Cons(y, Cons(x, Nil)) if x > 0 => x * 10 + y
+                | _ => 0
+
+            fn len2(l: List): Int = match l
+                | xs @ Cons(_, ys @ Cons(_, _)) => 2
+                | _ => 0
+
+            classify(0) + classify(1) * 10 + classify(5) * 100 + classify(50) * 1000 + sum(Cons(1, Cons(200, Cons(3, Nil)))) * 10000 + len2(Cons(1, Cons(2, Nil))) * 100000 + firsts(Cons(1, Cons(2, Cons(3, Nil)))) * 1000000
+            ",
+            "12243211",
+        );
+        check(
+            "
+            data List = Nil | Cons(Int, List)
+            fn f(l: List): Int = match l
+                | Cons(1, Cons(y, Nil)) => y
+                | Cons(-1, _) => 100
+                | Cons(_, Cons(_, Cons(z, _))) => z * 1000
+                | _ => 10000
+            fn g(c: Char, b: Bool): Int = match (c, b)
+                | ('a', true) => 1
+                | ('a', false) => 2
+                | (_, true) => 3
+                | (_, false) => 4
+            f(Cons(1, Cons(5, Nil))) + f(Cons(-1, Nil)) + f(Cons(2, Cons(2, Cons(7, Nil))))
+                + f(Nil) + (g('a', false) + g('b', true)) * 100000
+            ",
+            "517105",
+        );
+    }
+
+    // a nested match ends at an arm indented no deeper than its own, or at
+    // the brace that closes it
+    #[test]
+    fn nested_matches() {
+        check(
+            "
+            data List = Cons(Int, List) | Nil
+
+            fn get(list: List, i: Int): Int = match i
+                | 0 => match list
+                    | Cons(x, _) => x
+                    | Nil => 0 - 1
+                | _ => match list
+                    | Cons(_, xs) => get(xs, i - 1)
+                    | Nil => 0 - 1
+
+            fn sign(n: Int): Int = match n | 0 => 0
+                | _ => match n < 0 { | true => 0 - 1 | false => 1 }
+
+            fn both(a: Int, b: Int): Int = match a {
+                | 0 => match b {
+                | 0 => 1
+                | _ => 2
+                }
+                | _ => 3
+            }
+
+            let l = Cons(5, Cons(6, Cons(7, Nil)))
+            get(l, 0) + get(l, 2) * 10 + get(l, 5) * 100 + sign(0 - 4) * 1000 + sign(3) * 10000 + both(0, 1) * 100000 + both(2, 0) * 1000000
+            ",
+            "3208975",
+        );
+    }
+
+    // declared operators take their fixity, and the built-in ones keep theirs
+    #[test]
+    fn operator_declarations() {
+        check(
+            "
+            data List = Cons(Int, List) | Nil
+
+            infixl 1 |> = apply
+            infixr 5 ++ = append
+
+            fn apply(x: List, f: (List) -> Int): Int = f(x)
+
+            fn append(a: List, b: List): List = match a
+                | Nil => b
+                | Cons(x, rest) => Cons(x, rest ++ b)
+
+            fn sum(l: List): Int = match l
+                | Nil => 0
+                | Cons(x, rest) => x + sum(rest)
+
+            fn f(a: Int, b: Int, c: Int, d: Int): Bool = a < b && c < d || a == d
+
+            let l = Cons(1, Nil) ++ Cons(2, Nil) ++ Cons(3, Nil)
+            let b = if f(1, 2, 3, 4) 1 else 0
+            let c = if f(2, 1, 3, 4) 1 else 0
+            let d = if 7 % 4 != 3 1 else 0
+            let total = l |> sum
+            total * 1000 + 10 - 2 - 3 + b * 100 + c * 10 + d
+            ",
+            "6105",
+        );
+    }
+
+    // `n-1` subtracts, a leading minus negates, and patterns take a sign
+    #[test]
+    fn prefix_minus() {
+        check(
+            "
+            fn sign(n: Int): Int = match n
+                | -1 => 10
+                | 0 => 20
+                | _ => if n >= 0 1 else -1
+
+            fn neg(x: Int): Int = -x
+
+            let n = 5
+            let a = n-1
+            let f = -(2.5) * 2.0
+            let g = if -f > 4.0 1 else 0
+            let h = -neg(3) - -2
+            a * 1000 + sign(-1) + sign(-7) * 100 + g * 10000 + h * 100000 + (-3 * -4) * 1000000
+            ",
+            "12513910",
+        );
+        check(
+            "let m = -9223372036854775808\nm - 1",
+            "-9223372036854775809",
+        );
+    }
+
+    #[test]
+    fn sequencing() {
+        check(
+            r#"
+            fn show(n: Int): Int = {
+                print(int_to_string(n));
+                n * 2
+            }
+            let x = show(3)
+            let y = {
+                print("side");
+                show(x);
+                x + 1
+            }
+            print("done");
+            y
+            "#,
+            "7",
+        );
+    }
+
+    // clauses are tried in order, like the arms of a match
+    #[test]
+    fn function_clauses() {
+        check(
+            "
+            data List = Cons(Int, List) | Nil
+            data Pair = Pair(Int, Int)
+
+            fn swap(Pair(a, b)) = Pair(b, a)
+
+            fn len(Nil): Int = 0
+            fn len(Cons(_, xs)) = 1 + len(xs)
+
+            fn take(0, _) = Nil
+            fn take(_, Nil) = Nil
+            fn take(n, Cons(x, xs)) = Cons(x, take(n - 1, xs))
+
+            fn sum(l) = match l
+                | Nil => 0
+                | Cons(x, xs) => x + sum(xs)
+
+            fn first(p: Pair) = match p
+                | Pair(a, _) => a
+
+            fn zero() = 0
+
+            fn add(x: Int, y) = x + y
+
+            let l = Cons(1, Cons(2, Cons(3, Cons(4, Nil))))
+            first(swap(Pair(1, 2))) + len(l) * 10 + sum(take(2, l)) * 100 + add(zero(), 5) * 1000
+            ",
+            "5342",
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "can fail to match")]
+    fn refutable_let() {
+        run("
+            data E = L(Int) | R(Int)
+            let L(x) = R(3)
+            x
+            ");
+    }
+
+    #[test]
+    fn let_else() {
+        check(
+            "
+            data List = Cons(Int, List) | Nil
+            data Pair = Pair(Int, Int)
+
+            fn head_or(l: List, d: Int): Int = {
+                let Cons(x, _) = l else { d }
+                x
+            }
+
+            fn second(l: List): Int = {
+                let Cons(_, Cons(y, _)) = l else {
+                    let Cons(x, _) = l else { 0 - 1 }
+                    x * 100
+                }
+                y
+            }
+
+            let Pair(a, b) = Pair(3, 4)
+            let (c, _) = (5, 6)
+            head_or(Cons(7, Nil), 0) + head_or(Nil, 2) * 10 + second(Cons(1, Cons(9, Nil))) * 100 + second(Cons(8, Nil)) * 1000 + second(Nil) + a * 10000000 + b * 100000000 + c * 1000000000
+            ",
+            "5430800926",
+        );
+    }
+
+    // a failed guard goes on to the later arms, guarded or not
+    #[test]
+    fn match_guards() {
+        check(
+            "
+            fn f(n: Int): Int = match n
+                | x if x < 0 => 1
+                | x if x > 100 => 2
+                | 0 => 3
+                | _ => 4
+            f(-5) + f(500) * 10 + f(0) * 100 + f(7) * 1000
+            ",
+            "4321",
+        );
+    }
+
+    // the parameters made up for a function in several clauses must not
+    // capture a source variable
+    #[test]
+    fn clause_parameters_are_fresh() {
+        check(
+            "
+            data List = Nil | Cons(Int, List)
+            let arg_0 = 100
+            fn f(Nil, y) = arg_0 + y
+            fn f(Cons(x, _), y) = x
+            f(Nil, 3)
+            ",
+            "103",
+        );
+    }
+
+    // a minus is never part of the operator before it
+    #[test]
+    fn minus_after_operator() {
+        check(
+            "
+            fn sign(n: Int): Int = match n
+                |-1 => 1
+                | _ => 2
+            let x = 3
+            let a = if x==-3 0 else 1
+            x*-1 + sign(x*- 1+2) * 10 + a * 100 + (x--1) * 1000
+            ",
+            "4107",
+        );
+    }
+
+    // the alternatives cover E between them, so nothing fails to match
+    #[test]
+    fn exhaustive_or_pattern_let() {
+        check(
+            "
+            data E = L(Int) | R(Int)
+            let (L(x) | R(x)) = R(3)
+            let (true, y) | (false, y) = (false, x + 1)
+            x * 10 + y
+            ",
+            "34",
+        );
+    }
+
+    #[test]
+    fn trailing_semicolon() {
+        check(
+            "
+            fn f(x: Int) = {
+                print(int_to_string(x));
+                x + 1;
+            }
+            f(2);
+            f(3); 4
+            ",
+            "4",
+        );
+    }
+
+    // no clause applies to N
+    #[test]
+    fn match_failure() {
+        check(
+            "
+            data L = N | C(Int, L)
+            fn hd(C(h, _)) = h
+            hd(N)
+            ",
+            "Runtime error: pattern match failed",
+        );
+    }
+
+    // Each leaf that selects a guarded arm tests the guard, and goes on with
+    // the rows left in its branch. The guard binds y, which must be fresh in
+    // every copy.
+    #[test]
+    fn guards_in_several_leaves() {
+        check(
+            "
+            data L = N | C(Int, L)
+            fn f(l: L): Int = match l
+                | C(x, N) | C(_, C(x, _)) if {
+                    let y = x * 2
+                    y > 10
+                } => x
+                | C(x, C(_, _)) => 0 - x
+                | C(x, _) => x * 100
+                | N => 1000
+            f(C(1, C(9, N))) * 100000 + f(C(1, C(2, N))) * 10000 + f(C(6, N)) + f(C(2, N)) + f(N)
+            ",
+            "891206",
+        );
+    }
+}
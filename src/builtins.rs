@@ -1,122 +1,349 @@
+use crate::ast::Loc;
+use crate::integer::Integer;
 use crate::interp::*;
+use core::fmt;
+use std::cmp::Ordering;
 
 macro_rules! get_int {
     ($val:expr) => {
-        match $val {
+        match &$val {
             Value::Int(n) => n,
             _ => panic!("Expected Int, got: {}", $val),
         }
     };
 }
 
+macro_rules! get_float {
+    ($val:expr) => {
+        match &$val {
+            Value::Float(x) => *x,
+            _ => panic!("Expected Float, got: {}", $val),
+        }
+    };
+}
+
+macro_rules! get_str {
+    ($val:expr) => {
+        match &$val {
+            Value::Str(s) => s,
+            _ => panic!("Expected String, got: {}", $val),
+        }
+    };
+}
+
 macro_rules! get_bool {
     ($val:expr) => {
-        match $val {
-            Value::Bool(b) => b,
+        match &$val {
+            Value::Bool(b) => *b,
             _ => panic!("Expected Bool, got: {}", $val),
         }
     };
 }
 
-pub fn add(args:Vec<Value>) -> Value {
+// Ints grow as needed, so only division by zero (and shifting by a negative
+// or absurd amount) stops the program with a runtime error, the same way in
+// the interpreter, the CPS evaluator and the generated code
+pub fn checked_arith(op: &str, x: &Integer, y: &Integer) -> Result<Integer, &'static str> {
+    match op {
+        "+" => Ok(x.add(y)),
+        "-" => Ok(x.sub(y)),
+        "*" => Ok(x.mul(y)),
+        "/" => x.div(y),
+        "%" => x.rem(y),
+        "shl" => x.shl(y),
+        "shr" => x.shr(y),
+        _ => panic!("unknown arithmetic op: {}", op),
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct RuntimeError {
+    pub message: String,
+    pub loc: Option<Loc>,
+}
+
+impl fmt::Display for RuntimeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.loc {
+            Some(loc) => write!(f, "Runtime error at {}: {}", loc, self.message),
+            None => write!(f, "Runtime error: {}", self.message),
+        }
+    }
+}
+
+pub fn add(args:Vec<Value>) -> Result<Value, &'static str> {
     assert!(args.len() == 2);
     let x = get_int!(args[0]);
     let y = get_int!(args[1]);
-    Value::Int(x + y)
+    Ok(Value::Int(checked_arith("+", x, y)?))
 }
 
-pub fn sub(args: Vec<Value>) -> Value {
+pub fn sub(args: Vec<Value>) -> Result<Value, &'static str> {
     assert!(args.len() == 2);
     let x = get_int!(args[0]);
     let y = get_int!(args[1]);
-    Value::Int(x - y)
+    Ok(Value::Int(checked_arith("-", x, y)?))
 }
 
-pub fn mul(args: Vec<Value>) -> Value {
+pub fn mul(args: Vec<Value>) -> Result<Value, &'static str> {
     assert!(args.len() == 2);
     let x = get_int!(args[0]);
     let y = get_int!(args[1]);
-    Value::Int(x * y)
+    Ok(Value::Int(checked_arith("*", x, y)?))
 }
 
-pub fn div(args: Vec<Value>) -> Value {
+pub fn div(args: Vec<Value>) -> Result<Value, &'static str> {
     assert!(args.len() == 2);
     let x = get_int!(args[0]);
     let y = get_int!(args[1]);
-    Value::Int(x / y)
+    Ok(Value::Int(checked_arith("/", x, y)?))
 }
 
-pub fn mod_(args: Vec<Value>) -> Value {
+pub fn mod_(args: Vec<Value>) -> Result<Value, &'static str> {
     assert!(args.len() == 2);
     let x = get_int!(args[0]);
     let y = get_int!(args[1]);
-    Value::Int(x % y)
+    Ok(Value::Int(checked_arith("%", x, y)?))
 }
 
-pub fn bnot(args: Vec<Value>) -> Value {
+pub fn bnot(args: Vec<Value>) -> Result<Value, &'static str> {
     assert!(args.len() == 1);
     let x = get_bool!(args[0]);
-    Value::Bool(!x)
+    Ok(Value::Bool(!x))
 }
 
-pub fn eq(args: Vec<Value>) -> Value {
+// Ints and chars compare with the same operators
+fn order(x: &Value, y: &Value) -> Ordering {
+    match (x, y) {
+        (Value::Int(x), Value::Int(y)) => x.cmp(y),
+        (Value::Char(x), Value::Char(y)) => x.cmp(y),
+        _ => panic!("Cannot compare {} and {}", x, y),
+    }
+}
+
+pub fn eq(args: Vec<Value>) -> Result<Value, &'static str> {
     assert!(args.len() == 2);
-    let x = get_int!(args[0]);
-    let y = get_int!(args[1]);
-    Value::Bool(x == y)
+    Ok(Value::Bool(order(&args[0], &args[1]) == Ordering::Equal))
 }
 
-pub fn neq(args: Vec<Value>) -> Value {
+pub fn neq(args: Vec<Value>) -> Result<Value, &'static str> {
     assert!(args.len() == 2);
-    let x = get_int!(args[0]);
-    let y = get_int!(args[1]);
-    Value::Bool(x != y)
+    Ok(Value::Bool(order(&args[0], &args[1]) != Ordering::Equal))
 }
 
-pub fn lt(args: Vec<Value>) -> Value {
+pub fn lt(args: Vec<Value>) -> Result<Value, &'static str> {
     assert!(args.len() == 2);
-    let x = get_int!(args[0]);
-    let y = get_int!(args[1]);
-    Value::Bool(x < y)
+    Ok(Value::Bool(order(&args[0], &args[1]) < Ordering::Equal))
 }
 
-pub fn gt(args: Vec<Value>) -> Value {
+pub fn gt(args: Vec<Value>) -> Result<Value, &'static str> {
     assert!(args.len() == 2);
-    let x = get_int!(args[0]);
-    let y = get_int!(args[1]);
-    Value::Bool(x > y)
+    Ok(Value::Bool(order(&args[0], &args[1]) > Ordering::Equal))
 }
 
-pub fn le(args: Vec<Value>) -> Value {
+pub fn le(args: Vec<Value>) -> Result<Value, &'static str> {
     assert!(args.len() == 2);
-    let x = get_int!(args[0]);
-    let y = get_int!(args[1]);
-    Value::Bool(x <= y)
+    Ok(Value::Bool(order(&args[0], &args[1]) <= Ordering::Equal))
 }
 
-pub fn ge(args: Vec<Value>) -> Value {
+pub fn ge(args: Vec<Value>) -> Result<Value, &'static str> {
     assert!(args.len() == 2);
-    let x = get_int!(args[0]);
-    let y = get_int!(args[1]);
-    Value::Bool(x >= y)
+    Ok(Value::Bool(order(&args[0], &args[1]) >= Ordering::Equal))
 }
 
-pub fn and(args: Vec<Value>) -> Value {
+pub fn and(args: Vec<Value>) -> Result<Value, &'static str> {
     assert!(args.len() == 2);
     let x = get_bool!(args[0]);
     let y = get_bool!(args[1]);
-    Value::Bool(x && y)
+    Ok(Value::Bool(x && y))
 }
 
-pub fn or(args: Vec<Value>) -> Value {
+pub fn or(args: Vec<Value>) -> Result<Value, &'static str> {
     assert!(args.len() == 2);
     let x = get_bool!(args[0]);
     let y = get_bool!(args[1]);
-    Value::Bool(x || y)
+    Ok(Value::Bool(x || y))
 }
 
-pub fn not(args: Vec<Value>) -> Value {
+pub fn not(args: Vec<Value>) -> Result<Value, &'static str> {
     assert!(args.len() == 1);
     let x = get_bool!(args[0]);
-    Value::Bool(!x)
+    Ok(Value::Bool(!x))
+}
+
+pub fn fadd(args: Vec<Value>) -> Result<Value, &'static str> {
+    assert!(args.len() == 2);
+    let x = get_float!(args[0]);
+    let y = get_float!(args[1]);
+    Ok(Value::Float(x + y))
+}
+
+pub fn fsub(args: Vec<Value>) -> Result<Value, &'static str> {
+    assert!(args.len() == 2);
+    let x = get_float!(args[0]);
+    let y = get_float!(args[1]);
+    Ok(Value::Float(x - y))
+}
+
+pub fn fmul(args: Vec<Value>) -> Result<Value, &'static str> {
+    assert!(args.len() == 2);
+    let x = get_float!(args[0]);
+    let y = get_float!(args[1]);
+    Ok(Value::Float(x * y))
+}
+
+pub fn fdiv(args: Vec<Value>) -> Result<Value, &'static str> {
+    assert!(args.len() == 2);
+    let x = get_float!(args[0]);
+    let y = get_float!(args[1]);
+    Ok(Value::Float(x / y))
+}
+
+pub fn feq(args: Vec<Value>) -> Result<Value, &'static str> {
+    assert!(args.len() == 2);
+    let x = get_float!(args[0]);
+    let y = get_float!(args[1]);
+    Ok(Value::Bool(x == y))
+}
+
+pub fn fneq(args: Vec<Value>) -> Result<Value, &'static str> {
+    assert!(args.len() == 2);
+    let x = get_float!(args[0]);
+    let y = get_float!(args[1]);
+    Ok(Value::Bool(x != y))
+}
+
+pub fn flt(args: Vec<Value>) -> Result<Value, &'static str> {
+    assert!(args.len() == 2);
+    let x = get_float!(args[0]);
+    let y = get_float!(args[1]);
+    Ok(Value::Bool(x < y))
+}
+
+pub fn fgt(args: Vec<Value>) -> Result<Value, &'static str> {
+    assert!(args.len() == 2);
+    let x = get_float!(args[0]);
+    let y = get_float!(args[1]);
+    Ok(Value::Bool(x > y))
+}
+
+pub fn fle(args: Vec<Value>) -> Result<Value, &'static str> {
+    assert!(args.len() == 2);
+    let x = get_float!(args[0]);
+    let y = get_float!(args[1]);
+    Ok(Value::Bool(x <= y))
+}
+
+pub fn fge(args: Vec<Value>) -> Result<Value, &'static str> {
+    assert!(args.len() == 2);
+    let x = get_float!(args[0]);
+    let y = get_float!(args[1]);
+    Ok(Value::Bool(x >= y))
+}
+
+pub fn to_float(args: Vec<Value>) -> Result<Value, &'static str> {
+    assert!(args.len() == 1);
+    let x = get_int!(args[0]);
+    Ok(Value::Float(x.to_f64()))
+}
+
+pub fn truncate(args: Vec<Value>) -> Result<Value, &'static str> {
+    assert!(args.len() == 1);
+    let x = get_float!(args[0]);
+    Ok(Value::Int(Integer::truncate(x)?))
+}
+
+pub fn concat(args: Vec<Value>) -> Result<Value, &'static str> {
+    assert!(args.len() == 2);
+    let x = get_str!(args[0]);
+    let y = get_str!(args[1]);
+    Ok(Value::Str(format!("{}{}", x, y)))
+}
+
+pub fn length(args: Vec<Value>) -> Result<Value, &'static str> {
+    assert!(args.len() == 1);
+    let x = get_str!(args[0]);
+    Ok(Value::Int(str_length(x)))
+}
+
+pub fn char_at(args: Vec<Value>) -> Result<Value, &'static str> {
+    assert!(args.len() == 2);
+    let x = get_str!(args[0]);
+    let i = get_int!(args[1]);
+    Ok(Value::Char(checked_char_at(x, i)?))
+}
+
+pub fn substring(args: Vec<Value>) -> Result<Value, &'static str> {
+    assert!(args.len() == 3);
+    let x = get_str!(args[0]);
+    let start = get_int!(args[1]);
+    let end = get_int!(args[2]);
+    Ok(Value::Str(checked_substring(x, start, end)?))
+}
+
+pub fn compare(args: Vec<Value>) -> Result<Value, &'static str> {
+    assert!(args.len() == 2);
+    let x = get_str!(args[0]);
+    let y = get_str!(args[1]);
+    Ok(Value::Int(str_compare(x, y)))
+}
+
+pub fn int_to_string(args: Vec<Value>) -> Result<Value, &'static str> {
+    assert!(args.len() == 1);
+    let x = get_int!(args[0]);
+    Ok(Value::Str(x.to_string()))
+}
+
+pub fn string_to_int(args: Vec<Value>) -> Result<Value, &'static str> {
+    assert!(args.len() == 1);
+    let x = get_str!(args[0]);
+    Ok(Value::Int(checked_string_to_int(x)?))
+}
+
+pub fn print(args: Vec<Value>) -> Result<Value, &'static str> {
+    assert!(args.len() == 1);
+    let x = get_str!(args[0]);
+    println!("{}", x);
+    Ok(Value::Unit)
+}
+
+// Strings are sequences of chars, so lengths and indices count chars rather
+// than bytes, the same way in every evaluator and the generated code
+pub fn str_length(s: &str) -> Integer {
+    (s.chars().count() as i64).into()
+}
+
+fn index(s: &str, i: &Integer) -> Result<usize, &'static str> {
+    i.to_i64()
+        .and_then(|i| usize::try_from(i).ok())
+        .filter(|i| *i <= s.chars().count())
+        .ok_or("index out of bounds")
+}
+
+pub fn checked_char_at(s: &str, i: &Integer) -> Result<char, &'static str> {
+    s.chars().nth(index(s, i)?).ok_or("index out of bounds")
+}
+
+// the chars from start up to (not including) end
+pub fn checked_substring(s: &str, start: &Integer, end: &Integer) -> Result<String, &'static str> {
+    let start = index(s, start)?;
+    let end = index(s, end)?;
+    if start > end {
+        return Err("index out of bounds");
+    }
+    Ok(s.chars().skip(start).take(end - start).collect())
+}
+
+// -1, 0 or 1 as x sorts before, with or after y
+pub fn str_compare(x: &str, y: &str) -> Integer {
+    (x.cmp(y) as i64).into()
+}
+
+// an optional minus sign followed by digits
+pub fn checked_string_to_int(s: &str) -> Result<Integer, &'static str> {
+    let digits = s.strip_prefix('-').unwrap_or(s);
+    if digits.is_empty() || !digits.chars().all(|c| c.is_ascii_digit()) {
+        return Err("not an integer");
+    }
+    Ok(s.parse().unwrap())
 }
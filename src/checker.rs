@@ -1,4 +1,4 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use core::fmt;
 use crate::ast::*;
 
@@ -56,6 +56,7 @@ impl TySubst {
                 let new_ret = Box::new(self.apply(*ret));
                 Fn(new_args, new_ret)
             }
+            Tuple(types) => Tuple(types.into_iter().map(|t| self.apply(t)).collect()),
             _ => ty,
         }
     }
@@ -104,6 +105,18 @@ impl TyEnv {
                 (Name(String::from("&&")), bool_bool_op.clone()),
                 (Name(String::from("||")), bool_bool_op.clone()),
                 (Name(String::from("!")), bool_unary_op.clone()),
+
+                (Name(String::from("to_float")), Type::Fn(vec![Type::Int], Box::new(Type::Float))),
+                (Name(String::from("truncate")), Type::Fn(vec![Type::Float], Box::new(Type::Int))),
+
+                (Name(String::from("concat")), Type::Fn(vec![Type::Str, Type::Str], Box::new(Type::Str))),
+                (Name(String::from("length")), Type::Fn(vec![Type::Str], Box::new(Type::Int))),
+                (Name(String::from("char_at")), Type::Fn(vec![Type::Str, Type::Int], Box::new(Type::Char))),
+                (Name(String::from("substring")), Type::Fn(vec![Type::Str, Type::Int, Type::Int], Box::new(Type::Str))),
+                (Name(String::from("compare")), Type::Fn(vec![Type::Str, Type::Str], Box::new(Type::Int))),
+                (Name(String::from("int_to_string")), Type::Fn(vec![Type::Int], Box::new(Type::Str))),
+                (Name(String::from("string_to_int")), Type::Fn(vec![Type::Str], Box::new(Type::Int))),
+                (Name(String::from("print")), Type::Fn(vec![Type::Str], Box::new(Type::Unit))),
             ]),
         }
     }
@@ -142,6 +155,9 @@ fn ty_in(n: Type, m: Type) -> bool {
         (Fn(args, ret), TyVar(n)) => {
             args.iter().any(|a| ty_in(a.clone(), TyVar(n))) || ty_in(*ret, TyVar(n))
         }
+        (TyVar(n), Tuple(types)) | (Tuple(types), TyVar(n)) => {
+            types.into_iter().any(|t| ty_in(TyVar(n), t))
+        }
         (_, _) => false
     }
 }
@@ -171,6 +187,16 @@ fn unify(constraints: TyConstraints) -> TySubst {
 
             unify(new_constraints)
         }
+        (Tuple(types1), Tuple(types2)) if types1.len() == types2.len() => {
+            let mut new_constraints = types1
+                .iter()
+                .zip(types2.iter())
+                .map(|(t1, t2)| TyConstraint(t1.clone(), t2.clone()))
+                .collect::<Vec<_>>();
+            new_constraints.extend(rest);
+
+            unify(new_constraints)
+        }
         (TyVar(n), t) => {
             if ty_in(TyVar(*n), t.clone()) {
                 panic!("Type error: recursive type, T{} and {}", n, t);
@@ -195,6 +221,20 @@ fn unify(constraints: TyConstraints) -> TySubst {
 
             nsubst
         }
+        // an alias is the type it names; a clash right under it is reported
+        // with the alias name
+        (Alias(..), _) | (_, Alias(..)) => {
+            let (s, t) = (first.0.unalias(), first.1.unalias());
+            let clash = std::mem::discriminant(s) != std::mem::discriminant(t);
+            if clash && !matches!(s, TyVar(_)) && !matches!(t, TyVar(_)) {
+                panic!("Type error: cannot unify {} and {}", first.0, first.1);
+            }
+
+            let mut new_constraints = vec![TyConstraint(s.clone(), t.clone())];
+            new_constraints.extend(rest);
+
+            unify(new_constraints)
+        }
 
         _ => panic!("Type error: cannot unify {} and {}", first.0, first.1),
     }
@@ -202,34 +242,201 @@ fn unify(constraints: TyConstraints) -> TySubst {
 
 pub struct TypeChecker {
     cons_datadef: HashMap<Name, DataDef>, // from constructor name to DataDef
+    // operators that work on several types, with the type of their operands
+    overloaded: Vec<(Loc, Name, Type)>,
+    // record fields used, with the type of the record and of the field
+    fields: Vec<(Loc, Name, Type, Type)>,
+    // type aliases, by name
+    aliases: HashMap<Name, Type>,
+    // the types of values computed only for their effects
+    discarded: Vec<(Loc, Type)>,
+    // what infer warned about
+    warnings: Vec<String>,
+}
+
+// What the checker learned about the operators and record fields at each
+// place, to rewrite them with
+struct Resolved {
+    overloads: HashMap<Loc, Type>,
+    records: HashMap<Loc, DataDef>,
 }
 
 impl TypeChecker {
     pub fn new() -> Self {
         TypeChecker {
             cons_datadef: HashMap::new(),
+            overloaded: vec![],
+            fields: vec![],
+            aliases: HashMap::new(),
+            discarded: vec![],
+            warnings: vec![],
         }
     }
 
     pub fn infer(&mut self, program: Program) -> Program {
-        let (prog_ty, constraints) = self.infer_constraints(&program);
+        let (prog_ty, mut constraints) = self.infer_constraints(&program);
+
+        let mut subst = unify(constraints.clone());
 
-        let subst = unify(constraints);
-        let program = apply_subst_program(&subst, program);
+        // each record type found may tell the type of more fields
+        let mut records = HashMap::new();
+        loop {
+            let (found, known) = self.records(&subst);
+            if found.len() == records.len() {
+                break;
+            }
+            records = found;
+            constraints.extend(known);
+            subst = unify(constraints.clone());
+        }
+        let unknown = self.fields.iter().find(|(loc, ..)| !records.contains_key(loc));
+        if let Some((loc, field, _, _)) = unknown {
+            panic!("Type error: cannot tell which record has field {}, at {}", field, loc);
+        }
+
+        // operands nothing else pins down are ints
+        let defaults = self
+            .overloaded
+            .iter()
+            .filter(|(_, _, ty)| matches!(subst.apply(ty.clone()), Type::TyVar(_)))
+            .map(|(_, _, ty)| TyConstraint(ty.clone(), Type::Int))
+            .collect::<Vec<_>>();
+        if !defaults.is_empty() {
+            constraints.extend(defaults);
+            subst = unify(constraints);
+        }
+
+        let resolved = Resolved {
+            overloads: self.overloads(&subst),
+            records,
+        };
+        let program = apply_subst_program(&subst, &resolved, program);
         let prog_ty = subst.apply(prog_ty);
 
         if let Type::TyVar(_) = prog_ty {
             panic!("Type error: final type is unresolved");
         }
 
+        for (loc, ty) in &self.discarded {
+            let ty = subst.apply(ty.clone());
+            if !matches!(ty.unalias(), Type::Unit | Type::TyVar(_)) {
+                let warning = format!("Warning: a value of type {} is discarded, at {}", ty, loc);
+                eprintln!("{}", warning);
+                self.warnings.push(warning);
+            }
+        }
+
         program
     }
 
+    // The operators that need another version for their operands, by where
+    // they are. Chars are compared as ints.
+    fn overloads(&self, subst: &TySubst) -> HashMap<Loc, Type> {
+        let mut overloads = HashMap::new();
+        for (loc, op, ty) in &self.overloaded {
+            let arith = matches!(op.0.as_str(), "+" | "-" | "*" | "/");
+            let ty = subst.apply(ty.clone());
+            match ty.unalias() {
+                Type::Int => {}
+                Type::Char if !arith => {}
+                Type::Float => {
+                    overloads.insert(*loc, Type::Float);
+                }
+                Type::Str if !arith => {
+                    overloads.insert(*loc, Type::Str);
+                }
+                _ => panic!("Type error: {} is not defined on {}, at {}", op, ty, loc),
+            }
+        }
+        overloads
+    }
+
+    // The record type of each field use known so far, with what that says of
+    // the types involved. A field name used by just one record type tells the
+    // type on its own.
+    fn records(&self, subst: &TySubst) -> (HashMap<Loc, DataDef>, TyConstraints) {
+        let mut records = HashMap::new();
+        let mut known = vec![];
+        for (loc, field, t_record, t_field) in &self.fields {
+            let ty = subst.apply(t_record.clone());
+            let df = match ty.unalias() {
+                Type::UserDef(name) => self
+                    .cons_datadef
+                    .values()
+                    .find(|df| df.name == *name)
+                    .unwrap()
+                    .clone(),
+                Type::TyVar(_) => {
+                    let mut dfs = self.cons_datadef.values().filter(|df| {
+                        df.record().is_some_and(|(_, cons)| cons.fields.contains(field))
+                    });
+                    match (dfs.next(), dfs.next()) {
+                        (Some(df), None) => df.clone(),
+                        _ => continue,
+                    }
+                }
+                _ => panic!("Type error: {} has no field {}, at {}", ty, field, loc),
+            };
+
+            let Some((_, cons)) = df.record() else {
+                panic!("Type error: {} is not a record, at {}", df.name, loc);
+            };
+            let Some(i) = cons.fields.iter().position(|name| name == field) else {
+                panic!("Type error: {} has no field {}, at {}", df.name, field, loc);
+            };
+
+            known.push(TyConstraint(t_record.clone(), Type::UserDef(df.name.clone())));
+            known.push(TyConstraint(t_field.clone(), cons.args[i].clone()));
+            records.insert(*loc, df);
+        }
+        (records, known)
+    }
+
+    // A type as written, with the aliases in it spelled out
+    fn resolve(&self, ty: &Type) -> Type {
+        expand_aliases(&self.aliases, ty, &mut vec![])
+    }
+
+    fn bindings(&self, pat: &Pattern) -> Vec<(Name, Type)> {
+        pat.bindings()
+            .into_iter()
+            .map(|(name, ty)| (name, self.resolve(&ty)))
+            .collect()
+    }
+
+    // the type of one use of an operator that works on several types alike
+    fn overloaded_op(&mut self, op: &Name, arity: usize, loc: Loc) -> Type {
+        let t_operand = fresh_tv();
+        self.overloaded.push((loc, op.clone(), t_operand.clone()));
+
+        let t_out = match op.0.as_str() {
+            "+" | "-" | "*" | "/" => t_operand.clone(),
+            _ => Type::Bool,
+        };
+        Type::Fn(vec![t_operand; arity], Box::new(t_out))
+    }
+
     fn infer_constraints(&mut self, program: &Program) -> (Type, TyConstraints) {
+        for def in &program.type_defs {
+            let data = program.data_defs.iter().any(|df| df.name == def.name);
+            if data || self.aliases.contains_key(&def.name) {
+                panic!("Type error: type {} is defined twice", def.name);
+            }
+            self.aliases.insert(def.name.clone(), def.ty.clone());
+        }
+        // an alias that names itself has no meaning
+        for def in &program.type_defs {
+            self.resolve(&Type::UserDef(def.name.clone()));
+        }
+
         self.cons_datadef = program
             .data_defs
             .iter()
             .fold(HashMap::new(), |mut acc, datadef| {
+                let mut datadef = datadef.clone();
+                for cons in datadef.cons.values_mut() {
+                    cons.args = cons.args.iter().map(|ty| self.resolve(ty)).collect();
+                }
                 for cons in &datadef.cons {
                     acc.insert(cons.0.clone(), datadef.clone());
                 }
@@ -244,7 +451,10 @@ impl TypeChecker {
         use Expr::*;
         match exp {
             Bind(pat, simp, body) => {
-                let bindings = pat.bindings();
+                if pat.refutable() {
+                    panic!("Type error: let {} can fail to match; give it an else branch", pat);
+                }
+                let bindings = self.bindings(pat);
                 let (t_pat, x_pat) = self.infer_constraints_pat(env.clone(), pat);
                 env.extend(bindings);
 
@@ -260,12 +470,17 @@ impl TypeChecker {
                 (t_body, x)
             }
             FnDef(f, body) => {
-                let t_args = f.args.iter().map(|(_, ty)| ty.clone()).collect();
-                let t_fret = f.ret.clone();
+                let args = f
+                    .args
+                    .iter()
+                    .map(|(name, ty)| (name.clone(), self.resolve(ty)))
+                    .collect::<Vec<_>>();
+                let t_args = args.iter().map(|(_, ty)| ty.clone()).collect();
+                let t_fret = self.resolve(&f.ret);
                 let t_fn = Type::Fn(t_args, Box::new(t_fret.clone()));
 
                 let mut fnenv = env.clone();
-                fnenv.extend(f.args.clone());
+                fnenv.extend(args);
                 fnenv.insert(f.name.clone(), t_fn.clone());
 
                 let (t_fbody, x_fbody) = self.infer_constraints_simp(fnenv, &*f.body);
@@ -282,6 +497,16 @@ impl TypeChecker {
 
                 (t_body, x)
             }
+            Seq(simp, loc, body) => {
+                let (t_simp, x_simp) = self.infer_constraints_simp(env.clone(), simp);
+                self.discarded.push((*loc, t_simp));
+
+                let (t_body, x_body) = self.infer_constraints_expr(env, body);
+
+                let mut x = x_simp;
+                x.extend(x_body);
+                (t_body, x)
+            }
             Simp(simp) => self.infer_constraints_simp(env, simp),
         }
     }
@@ -289,10 +514,12 @@ impl TypeChecker {
     fn infer_constraints_pat(&mut self, env: TyEnv, pat: &Pattern) -> (Type, TyConstraints) {
         use Pattern::*;
         match pat {
-            Var(_, ty) => (ty.clone(), vec![]),
+            Var(_, ty) => (self.resolve(ty), vec![]),
             Int(_) => (Type::Int, vec![]),
+            Char(_) => (Type::Char, vec![]),
             Bool(_) => (Type::Bool, vec![]),
-            Data(datadef, name, pats) => {
+            Data(_, name, pats) => {
+                let datadef = self.cons_datadef[name].clone();
                 let cons = datadef.cons.get(name).unwrap();
                 let mut x_out = vec![];
                 let mut t_args = vec![];
@@ -306,6 +533,51 @@ impl TypeChecker {
 
                 (Type::UserDef(datadef.name.clone()), x_out)
             }
+            Tuple(pats) => {
+                let mut x_out = vec![];
+                let mut t_pats = vec![];
+
+                for pat in pats {
+                    let (t_pat, x_pat) = self.infer_constraints_pat(env.clone(), pat);
+                    x_out.extend(x_pat);
+                    t_pats.push(t_pat);
+                }
+
+                (Type::Tuple(t_pats), x_out)
+            }
+            Wildcard => (fresh_tv(), vec![]),
+            Or(alts) => {
+                let (t_alt_0, mut x_out) = self.infer_constraints_pat(env.clone(), &alts[0]);
+                let bindings = self.bindings(&alts[0]);
+
+                for alt in &alts[1..] {
+                    let (t_alt, x_alt) = self.infer_constraints_pat(env.clone(), alt);
+                    x_out.extend(x_alt);
+                    x_out.push(TyConstraint(t_alt_0.clone(), t_alt));
+
+                    let alt_bindings = self.bindings(alt);
+                    let names = alt_bindings.iter().map(|(name, _)| name).collect::<HashSet<_>>();
+                    if names != bindings.iter().map(|(name, _)| name).collect() {
+                        panic!(
+                            "Type error: alternatives {} and {} bind different names",
+                            alts[0], alt
+                        );
+                    }
+
+                    for (name, ty) in alt_bindings {
+                        let t_first = &bindings.iter().find(|(first, _)| *first == name).unwrap().1;
+                        x_out.push(TyConstraint(t_first.clone(), ty));
+                    }
+                }
+
+                (t_alt_0, x_out)
+            }
+            As(_, ty, pat) => {
+                let (t_pat, mut x_out) = self.infer_constraints_pat(env, pat);
+                let t_as = self.resolve(ty);
+                x_out.push(TyConstraint(t_as.clone(), t_pat));
+                (t_as, x_out)
+            }
         }
     }
 
@@ -318,15 +590,22 @@ impl TypeChecker {
 
                 let mut t_arms = vec![];
 
-                for (pat, simp) in arms {
+                for (pat, guard, simp) in arms {
                     let (t_pat, x_pat) = self.infer_constraints_pat(env.clone(), pat);
                     x_out.extend(x_pat);
                     x_out.push(TyConstraint(t_simp.clone(), t_pat.clone()));
 
                     let mut env_arm = env.clone();
-                    let bindings = pat.bindings();
+                    let bindings = self.bindings(pat);
                     env_arm.extend(bindings);
 
+                    if let Some(guard) = guard {
+                        let (t_guard, x_guard) =
+                            self.infer_constraints_simp(env_arm.clone(), guard);
+                        x_out.extend(x_guard);
+                        x_out.push(TyConstraint(Type::Bool, t_guard));
+                    }
+
                     let (t_arm, x_arm) = self.infer_constraints_simp(env_arm, simp);
                     x_out.extend(x_arm);
                     t_arms.push(t_arm);
@@ -339,8 +618,13 @@ impl TypeChecker {
 
                 (t_arm_0, x_out)
             }
-            FnCall(lhs, args) => {
-                let (t_lhs, x_lhs) = self.infer_constraints_simp(env.clone(), lhs);
+            FnCall(lhs, args, loc) => {
+                let (t_lhs, x_lhs) = match lhs.as_ref() {
+                    Ref(op) if float_op(op).is_some() => {
+                        (self.overloaded_op(op, args.len(), *loc), vec![])
+                    }
+                    _ => self.infer_constraints_simp(env.clone(), lhs),
+                };
                 let mut x_out = x_lhs;
                 let mut t_args = vec![];
 
@@ -359,6 +643,9 @@ impl TypeChecker {
             Block(expr) => self.infer_constraints_expr(env, expr),
             Ref(name) => (env.get(name), vec![]),
             Int(_) => (Type::Int, vec![]),
+            Float(_) => (Type::Float, vec![]),
+            Str(_) => (Type::Str, vec![]),
+            Char(_) => (Type::Char, vec![]),
             Bool(_) => (Type::Bool, vec![]),
             Unit => (Type::Unit, vec![]),
             Data(name, args) => {
@@ -384,12 +671,75 @@ impl TypeChecker {
 
                 (Type::UserDef(df.name), x_out)
             }
+            Tuple(args) => {
+                let mut x_out = vec![];
+                let mut t_args = vec![];
+
+                for arg in args {
+                    let (t_arg, x_arg) = self.infer_constraints_simp(env.clone(), arg);
+                    x_out.extend(x_arg);
+                    t_args.push(t_arg);
+                }
+
+                (Type::Tuple(t_args), x_out)
+            }
+            Field(record, field, _, loc) => {
+                let (t_record, x_out) = self.infer_constraints_simp(env.clone(), record);
+                let t_field = fresh_tv();
+                self.fields.push((*loc, field.clone(), t_record, t_field.clone()));
+
+                (t_field, x_out)
+            }
+            Update(record, fields, _, loc) => {
+                let (t_record, mut x_out) = self.infer_constraints_simp(env.clone(), record);
+
+                for (field, value) in fields {
+                    let (t_value, x_value) = self.infer_constraints_simp(env.clone(), value);
+                    x_out.extend(x_value);
+                    self.fields.push((*loc, field.clone(), t_record.clone(), t_value));
+                }
+
+                (t_record, x_out)
+            }
+        }
+    }
+}
+
+// ty with the aliases in it replaced by what they name, kept as Alias to show
+// their names in errors; path holds the aliases being spelled out
+fn expand_aliases(aliases: &HashMap<Name, Type>, ty: &Type, path: &mut Vec<Name>) -> Type {
+    use Type::*;
+    match ty {
+        UserDef(name) if aliases.contains_key(name) => {
+            if let Some(start) = path.iter().position(|alias| alias == name) {
+                let cycle = path[start..].iter().map(|alias| alias.0.as_str()).collect::<Vec<_>>();
+                panic!(
+                    "Type error: type alias {} refers to itself, through {} -> {}",
+                    name,
+                    cycle.join(" -> "),
+                    name
+                );
+            }
+
+            path.push(name.clone());
+            let named = expand_aliases(aliases, &aliases[name], path);
+            path.pop();
+
+            Alias(name.clone(), Box::new(named))
         }
+        Fn(args, ret) => Fn(
+            args.iter().map(|arg| expand_aliases(aliases, arg, path)).collect(),
+            Box::new(expand_aliases(aliases, ret, path)),
+        ),
+        Tuple(types) => Tuple(types.iter().map(|ty| expand_aliases(aliases, ty, path)).collect()),
+        _ => ty.clone(),
     }
 }
 
-fn apply_subst_program(subst: &TySubst, program: Program) -> Program {
-    let new_expr = program.expr.map(|e| apply_subst_expr(subst, e));
+// Also gives overloaded operators their version for the type they are used on,
+// and record fields their index
+fn apply_subst_program(subst: &TySubst, resolved: &Resolved, program: Program) -> Program {
+    let new_expr = program.expr.map(|e| apply_subst_expr(subst, resolved, e));
 
     Program {
         expr: new_expr,
@@ -397,30 +747,35 @@ fn apply_subst_program(subst: &TySubst, program: Program) -> Program {
     }
 }
 
-fn apply_subst_expr(subst: &TySubst, expr: Expr) -> Expr {
+fn apply_subst_expr(subst: &TySubst, resolved: &Resolved, expr: Expr) -> Expr {
     match expr {
         Expr::Bind(pat, simp, body) => {
             let new_pat = apply_subst_pat(subst, pat);
-            let new_simp = apply_subst_simp(subst, simp);
-            let new_body = apply_subst_expr(subst, *body);
+            let new_simp = apply_subst_simp(subst, resolved, simp);
+            let new_body = apply_subst_expr(subst, resolved, *body);
             Expr::Bind(new_pat, new_simp, Box::new(new_body))
         }
         Expr::FnDef(f, body) => {
             let new_args = f.args.iter().map(|(n, t)| (n.clone(), subst.apply(t.clone()))).collect();
-            let new_body = apply_subst_simp(subst, *f.body);
+            let new_body = apply_subst_simp(subst, resolved, *f.body);
             let new_ret = subst.apply(f.ret.clone());
             Expr::FnDef(FnDef {
                 name: f.name,
                 args: new_args,
                 body: Box::new(new_body),
                 ret: new_ret,
-            }, Box::new(apply_subst_expr(subst, *body)))
+            }, Box::new(apply_subst_expr(subst, resolved, *body)))
+        }
+        Expr::Seq(simp, loc, body) => {
+            let new_simp = apply_subst_simp(subst, resolved, simp);
+            let new_body = apply_subst_expr(subst, resolved, *body);
+            Expr::Seq(new_simp, loc, Box::new(new_body))
         }
-        Expr::Simp(simp) => Expr::Simp(apply_subst_simp(subst, simp)),
+        Expr::Simp(simp) => Expr::Simp(apply_subst_simp(subst, resolved, simp)),
     }
 }
 
-fn apply_subst_simp(subst: &TySubst, simp: Simp) -> Simp {
+fn apply_subst_simp(subst: &TySubst, resolved: &Resolved, simp: Simp) -> Simp {
     match simp {
         // Simp::FnDef(f) => {
         //     let new_args = f.args.iter().map(|(n, t)| (n.clone(), subst.apply(t.clone()))).collect();
@@ -433,40 +788,135 @@ fn apply_subst_simp(subst: &TySubst, simp: Simp) -> Simp {
         //     })
         // }
         Simp::Match(s, arms) => {
-            let new_s = apply_subst_simp(subst, *s);
+            let new_s = apply_subst_simp(subst, resolved, *s);
             let new_arms = arms
                 .into_iter()
-                .map(|(pat, simp)| (apply_subst_pat(subst, pat), apply_subst_simp(subst, simp)))
+                .map(|(pat, guard, simp)| {
+                    (
+                        apply_subst_pat(subst, pat),
+                        guard.map(|guard| apply_subst_simp(subst, resolved, guard)),
+                        apply_subst_simp(subst, resolved, simp),
+                    )
+                })
                 .collect();
             Simp::Match(Box::new(new_s), new_arms)
         }
-        Simp::FnCall(s, args) => {
-            let new_s = apply_subst_simp(subst, *s);
-            let new_args = args.into_iter().map(|a| apply_subst_simp(subst, a)).collect();
-            Simp::FnCall(Box::new(new_s), new_args)
+        Simp::FnCall(s, args, loc) => {
+            let mut new_args: Vec<_> =
+                args.into_iter().map(|a| apply_subst_simp(subst, resolved, a)).collect();
+            // negation subtracts from zero, and -0.0 gives -0.0 - x = -x for
+            // every float
+            if new_args.len() == 1 && matches!(s.as_ref(), Simp::Ref(op) if op.0 == "-") {
+                let zero = match resolved.overloads.get(&loc) {
+                    Some(Type::Float) => Simp::Float(-0.0),
+                    _ => Simp::Int(0.into()),
+                };
+                new_args.insert(0, zero);
+            }
+            match (*s, resolved.overloads.get(&loc)) {
+                (Simp::Ref(op), Some(Type::Float)) => {
+                    Simp::FnCall(Box::new(Simp::Ref(float_op(&op).unwrap())), new_args, loc)
+                }
+                // strings compare by the sign of `compare`
+                (Simp::Ref(op), Some(Type::Str)) => {
+                    let order = Simp::FnCall(Box::new(Simp::Ref(Name::new("compare"))), new_args, loc);
+                    Simp::FnCall(Box::new(Simp::Ref(op)), vec![order, Simp::Int(0.into())], loc)
+                }
+                (s, _) => Simp::FnCall(Box::new(apply_subst_simp(subst, resolved, s)), new_args, loc),
+            }
         }
-        Simp::Block(e) => Simp::Block(Box::new(apply_subst_expr(subst, *e))),
+        Simp::Block(e) => Simp::Block(Box::new(apply_subst_expr(subst, resolved, *e))),
         Simp::Ref(n) => Simp::Ref(n),
         Simp::Int(i) => Simp::Int(i),
+        Simp::Float(x) => Simp::Float(x),
+        Simp::Str(s) => Simp::Str(s),
+        Simp::Char(c) => Simp::Char(c),
         Simp::Bool(b) => Simp::Bool(b),
         Simp::Unit => Simp::Unit,
         Simp::Data(n, args) => {
-            let new_args = args.into_iter().map(|a| apply_subst_simp(subst, a)).collect();
+            let new_args = args.into_iter().map(|a| apply_subst_simp(subst, resolved, a)).collect();
             Simp::Data(n, new_args)
         }
+        Simp::Tuple(args) => {
+            let new_args = args.into_iter().map(|a| apply_subst_simp(subst, resolved, a)).collect();
+            Simp::Tuple(new_args)
+        }
+        Simp::Field(record, field, _, loc) => {
+            let (_, cons) = resolved.records[&loc].record().unwrap();
+            let index = cons.fields.iter().position(|name| *name == field);
+            let record = apply_subst_simp(subst, resolved, *record);
+            Simp::Field(Box::new(record), field, index, loc)
+        }
+        Simp::Update(record, fields, _, loc) => {
+            let (name, cons) = resolved.records[&loc].record().unwrap();
+            let indices = fields
+                .iter()
+                .map(|(field, _)| cons.fields.iter().position(|name| name == field).unwrap())
+                .collect();
+            let record = apply_subst_simp(subst, resolved, *record);
+            let fields = fields
+                .into_iter()
+                .map(|(field, value)| (field, apply_subst_simp(subst, resolved, value)))
+                .collect();
+            Simp::Update(Box::new(record), fields, Some((name.clone(), indices)), loc)
+        }
     }
 }
 
-
 fn apply_subst_pat(subst: &TySubst, pat: Pattern) -> Pattern {
     use Pattern::*;
     match pat {
         Var(name, ty) => Var(name, subst.apply(ty)),
         Int(i) => Int(i),
+        Char(c) => Char(c),
         Bool(b) => Bool(b),
         Data(data, name, pats) => {
             let new_pats = pats.iter().map(|p| apply_subst_pat(subst, p.clone())).collect();
             Data(data, name, new_pats)
         }
+        Tuple(pats) => Tuple(pats.into_iter().map(|p| apply_subst_pat(subst, p)).collect()),
+        Wildcard => Wildcard,
+        Or(alts) => Or(alts.into_iter().map(|p| apply_subst_pat(subst, p)).collect()),
+        As(name, ty, pat) => As(name, subst.apply(ty), Box::new(apply_subst_pat(subst, *pat))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use logos::Logos;
+
+    use super::*;
+    use crate::{lexer::Token, parser::Parser};
+
+    fn warnings(source: &str) -> Vec<String> {
+        let program = Parser::new(Token::lexer(source)).parse_program();
+        let mut checker = TypeChecker::new();
+        checker.infer(program);
+        checker.warnings
+    }
+
+    #[test]
+    fn discarded_values() {
+        assert_eq!(
+            warnings("1 + 2; print(\"a\"); 3"),
+            ["Warning: a value of type Int is discarded, at 1:1"]
+        );
+        assert!(warnings("print(\"a\"); { print(\"b\"); () }; 3").is_empty());
     }
-}
\ No newline at end of file
+
+    // a trailing `;` discards the last value of a block, which is then ()
+    #[test]
+    fn trailing_semicolon() {
+        let source = "
+            fn f(x: Int): Unit = {
+                print(int_to_string(x));
+                x + 1;
+            }
+            f(2)
+        ";
+        assert_eq!(
+            warnings(source),
+            ["Warning: a value of type Int is discarded, at 4:17"]
+        );
+    }
+}
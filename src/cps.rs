@@ -1,9 +1,41 @@
-use crate::ast::{Name, Op};
-use std::{collections::HashSet, fmt::Display};
+use crate::ast::{Loc, Name, Op};
+use crate::integer::Integer;
+use crate::lexer::escape;
+use std::{
+    collections::HashSet,
+    fmt::Display,
+    hash::{Hash, Hasher},
+};
 
-#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+#[derive(Debug, Clone)]
 pub enum LitHigh {
-    Int(i64),
+    Int(Integer),
+    Float(f64),
+    Str(String),
+}
+
+// Floats compare by their bits, so a literal is always equal to itself
+impl PartialEq for LitHigh {
+    fn eq(&self, other: &Self) -> bool {
+        match (self, other) {
+            (LitHigh::Int(x), LitHigh::Int(y)) => x == y,
+            (LitHigh::Float(x), LitHigh::Float(y)) => x.to_bits() == y.to_bits(),
+            (LitHigh::Str(x), LitHigh::Str(y)) => x == y,
+            _ => false,
+        }
+    }
+}
+
+impl Eq for LitHigh {}
+
+impl Hash for LitHigh {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        match self {
+            LitHigh::Int(n) => n.hash(state),
+            LitHigh::Float(x) => x.to_bits().hash(state),
+            LitHigh::Str(s) => s.hash(state),
+        }
+    }
 }
 
 #[derive(Debug, Clone, PartialEq)]
@@ -17,6 +49,8 @@ pub enum CpsExpr<Lit> {
         name: Name,
         op: Name,
         args: Vec<Name>,
+        // where in the source a trapping op can fail
+        loc: Option<Loc>,
         body: Box<CpsExpr<Lit>>,
     },
 
@@ -68,11 +102,13 @@ impl<T: Clone> Substitutable for CpsExpr<T> {
                 name,
                 op,
                 args,
+                loc,
                 body,
             } => Prim {
                 name: subst.apply(name),
                 op: op.clone(),
                 args: args.iter().map(|a| subst.apply(a)).collect(),
+                loc: *loc,
                 body: Box::new(body.subst(subst)),
             },
             Cnts { cnts, body } => Cnts {
@@ -180,10 +216,67 @@ impl Subst {
     }
 }
 
+// The runtime functions arithmetic calls in the printed code, so ints can grow
+// past a machine word and floats keep their own operators
+const ARITH_HELPERS: [(&str, &str); 17] = [
+    ("+", "add"),
+    ("-", "sub"),
+    ("*", "mul"),
+    ("/", "div"),
+    ("%", "rem"),
+    ("shl", "shl"),
+    ("shr", "shr"),
+    ("+.", "fadd"),
+    ("-.", "fsub"),
+    ("*.", "fmul"),
+    ("/.", "fdiv"),
+    ("==.", "feq"),
+    ("!=.", "fneq"),
+    ("<.", "flt"),
+    (">.", "fgt"),
+    ("<=.", "fle"),
+    (">=.", "fge"),
+];
+
+pub fn arith_helper(op: &Name) -> Option<&'static str> {
+    ARITH_HELPERS
+        .iter()
+        .find(|(name, _)| op.0 == *name)
+        .map(|(_, helper)| *helper)
+}
+
+// The op a runtime function in the printed code stands for
+pub fn arith_op(helper: &str) -> Option<Name> {
+    ARITH_HELPERS
+        .iter()
+        .find(|(_, name)| helper == *name)
+        .map(|(op, _)| Name::new(op))
+}
+
+// Ops that can stop the program with a runtime error (division by zero, a
+// shift out of range, truncating infinity, indexing past the end of a string
+// or reading an int from one that isn't), naming their source location
+pub fn traps(op: &Name) -> bool {
+    matches!(
+        op.0.as_str(),
+        "/" | "%" | "shl" | "shr" | "truncate" | "char_at" | "substring" | "string_to_int"
+    )
+}
+
 impl Display for LitHigh {
     fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
         match self {
+            // JS numbers are exact only up to 2^53, so larger ones are bigints
+            LitHigh::Int(n) if n.to_i64().is_none_or(|n| n.unsigned_abs() >= 1 << 53) => {
+                write!(f, "{}n", n)
+            }
             LitHigh::Int(n) => write!(f, "{}", n),
+            // always with a point or an exponent, to tell them from ints
+            LitHigh::Float(x) if x.is_nan() => write!(f, "NaN"),
+            LitHigh::Float(x) if x.is_infinite() && *x > 0.0 => write!(f, "Infinity"),
+            LitHigh::Float(x) if x.is_infinite() => write!(f, "-Infinity"),
+            LitHigh::Float(x) => write!(f, "{:?}", x),
+            LitHigh::Str(s) => write!(f, "{}", escape(s)),
         }
     }
 }
@@ -198,9 +291,20 @@ impl<Lit: Display> Display for CpsExpr<Lit> {
                 name,
                 op,
                 args,
+                loc,
                 body,
             } => {
-                if op.valid() {
+                let call = |fun: &str| {
+                    let mut args = args.iter().map(|a| a.to_string()).collect::<Vec<_>>();
+                    if let Some(loc) = loc {
+                        args.push(format!("\"{}\"", loc));
+                    }
+                    format!("{}({})", fun, args.join(", "))
+                };
+
+                if let Some(helper) = arith_helper(op) {
+                    write!(f, "let {} = {};\n{}", name, call(helper), body)
+                } else if op.valid() {
                     if op.unary() {
                         assert!(args.len() == 1);
                         write!(f, "let {} = {} {};\n{}", name, op, args[0], body)
@@ -213,17 +317,7 @@ impl<Lit: Display> Display for CpsExpr<Lit> {
                         )
                     }
                 } else {
-                    write!(
-                        f,
-                        "let {} = {}({});\n{}",
-                        name,
-                        op,
-                        args.iter()
-                            .map(|a| a.to_string())
-                            .collect::<Vec<_>>()
-                            .join(", "),
-                        body
-                    )
+                    write!(f, "let {} = {};\n{}", name, call(&op.0), body)
                 }
             }
             Cnts { cnts, body } => {
@@ -267,7 +361,19 @@ impl<Lit: Display> Display for CpsExpr<Lit> {
                 t: tr,
                 f: fl,
             } => {
-                if op.valid() {
+                if let Some(helper) = arith_helper(op) {
+                    write!(
+                        f,
+                        "if ({}({})) {{ {}() }} else {{ {}() }}",
+                        helper,
+                        args.iter()
+                            .map(|a| a.to_string())
+                            .collect::<Vec<_>>()
+                            .join(", "),
+                        tr,
+                        fl
+                    )
+                } else if op.valid() {
                     if op.unary() {
                         assert!(args.len() == 1);
                         write!(
@@ -334,7 +440,8 @@ impl<Lit> CpsExpr<Lit> {
         use CpsExpr::*;
         match self {
             Const { name, body, .. } => &body.free() - hs_name!(name),
-            Prim { name, op, body, .. } => &(&body.free() - hs_name!(name)) | hs_name!(op),
+            #[rustfmt::skip]
+            Prim { name, op, args, body, .. } => &(&(&body.free() - hs_name!(name)) | hs_name!(op)) | &args.iter().cloned().collect(),
             Cnts { cnts, body, .. } => {
                 let cnts_free = cnts
                     .iter()
@@ -362,6 +469,173 @@ impl<Lit> CpsExpr<Lit> {
             Halt(name) => hs_name!(name).clone(),
         }
     }
+
+    pub fn binders(&self) -> Vec<Name> {
+        use CpsExpr::*;
+        match self {
+            Const { name, body, .. } | Prim { name, body, .. } => {
+                let mut out = vec![name.clone()];
+                out.extend(body.binders());
+                out
+            }
+            Cnts { cnts, body } => {
+                let mut out = vec![];
+                for cnt in cnts {
+                    out.push(cnt.name.clone());
+                    out.extend(cnt.args.iter().cloned());
+                    out.extend(cnt.body.binders());
+                }
+                out.extend(body.binders());
+                out
+            }
+            Funs { funs, body } => {
+                let mut out = vec![];
+                for fun in funs {
+                    out.push(fun.name.clone());
+                    out.push(fun.ret.clone());
+                    out.extend(fun.args.iter().cloned());
+                    out.extend(fun.body.binders());
+                }
+                out.extend(body.binders());
+                out
+            }
+            AppC { .. } | AppF { .. } | If { .. } | Halt(_) => vec![],
+        }
+    }
+}
+
+// Pairs up the binders of two trees being compared for alpha-equivalence
+#[derive(Clone, Default)]
+struct Pairing {
+    left: std::collections::HashMap<Name, Name>,
+    right: std::collections::HashMap<Name, Name>,
+}
+
+impl Pairing {
+    fn bind(&mut self, l: &Name, r: &Name) {
+        self.left.insert(l.clone(), r.clone());
+        self.right.insert(r.clone(), l.clone());
+    }
+
+    fn bind_all(&mut self, ls: &[Name], rs: &[Name]) -> bool {
+        ls.len() == rs.len() && {
+            ls.iter().zip(rs).for_each(|(l, r)| self.bind(l, r));
+            true
+        }
+    }
+
+    // bound names must be bound by paired binders, free names must be equal
+    fn same(&self, l: &Name, r: &Name) -> bool {
+        match (self.left.get(l), self.right.get(r)) {
+            (Some(lr), Some(rl)) => lr == r && rl == l,
+            (None, None) => l == r,
+            _ => false,
+        }
+    }
+
+    fn same_all(&self, ls: &[Name], rs: &[Name]) -> bool {
+        ls.len() == rs.len() && ls.iter().zip(rs).all(|(l, r)| self.same(l, r))
+    }
+}
+
+impl<Lit: PartialEq> CpsExpr<Lit> {
+    // Equal up to a consistent renaming of bound names
+    pub fn alpha_eq(&self, other: &CpsExpr<Lit>) -> bool {
+        Self::alpha(self, other, Pairing::default())
+    }
+
+    fn alpha(l: &CpsExpr<Lit>, r: &CpsExpr<Lit>, mut env: Pairing) -> bool {
+        use CpsExpr::*;
+        match (l, r) {
+            (
+                Const { name: ln, value: lv, body: lb },
+                Const { name: rn, value: rv, body: rb },
+            ) => {
+                env.bind(ln, rn);
+                lv == rv && Self::alpha(lb, rb, env)
+            }
+            (
+                Prim { name: ln, op: lo, args: la, loc: lloc, body: lb },
+                Prim { name: rn, op: ro, args: ra, loc: rloc, body: rb },
+            ) => {
+                let args = env.same(lo, ro) && env.same_all(la, ra) && lloc == rloc;
+                env.bind(ln, rn);
+                args && Self::alpha(lb, rb, env)
+            }
+            (Cnts { cnts: lc, body: lb }, Cnts { cnts: rc, body: rb }) => {
+                let lnames = lc.iter().map(|cnt| cnt.name.clone()).collect::<Vec<_>>();
+                let rnames = rc.iter().map(|cnt| cnt.name.clone()).collect::<Vec<_>>();
+                if !env.bind_all(&lnames, &rnames) {
+                    return false;
+                }
+
+                lc.iter().zip(rc).all(|(l, r)| {
+                    let mut cenv = env.clone();
+                    cenv.bind_all(&l.args, &r.args) && Self::alpha(&l.body, &r.body, cenv)
+                }) && Self::alpha(lb, rb, env)
+            }
+            (Funs { funs: lf, body: lb }, Funs { funs: rf, body: rb }) => {
+                let lnames = lf.iter().map(|fun| fun.name.clone()).collect::<Vec<_>>();
+                let rnames = rf.iter().map(|fun| fun.name.clone()).collect::<Vec<_>>();
+                if !env.bind_all(&lnames, &rnames) {
+                    return false;
+                }
+
+                lf.iter().zip(rf).all(|(l, r)| {
+                    let mut fenv = env.clone();
+                    fenv.bind(&l.ret, &r.ret);
+                    fenv.bind_all(&l.args, &r.args) && Self::alpha(&l.body, &r.body, fenv)
+                }) && Self::alpha(lb, rb, env)
+            }
+            (AppC { cnt: lc, args: la }, AppC { cnt: rc, args: ra }) => {
+                env.same(lc, rc) && env.same_all(la, ra)
+            }
+            (
+                AppF { fun: lf, ret: lr, args: la },
+                AppF { fun: rf, ret: rr, args: ra },
+            ) => env.same(lf, rf) && env.same(lr, rr) && env.same_all(la, ra),
+            (
+                If { op: lo, args: la, t: lt, f: lf },
+                If { op: ro, args: ra, t: rt, f: rf },
+            ) => env.same(lo, ro) && env.same_all(la, ra) && env.same(lt, rt) && env.same(lf, rf),
+            (Halt(l), Halt(r)) => env.same(l, r),
+            _ => false,
+        }
+    }
+}
+
+// Hands out names that are not bound or referenced anywhere in a tree
+pub struct NameSupply {
+    taken: HashSet<Name>,
+    counts: std::collections::HashMap<String, usize>,
+}
+
+impl NameSupply {
+    pub fn new<Lit>(tree: &CpsExpr<Lit>) -> Self {
+        let mut taken = tree.free();
+        taken.extend(tree.binders());
+        Self {
+            taken,
+            counts: std::collections::HashMap::new(),
+        }
+    }
+
+    pub fn fresh(&mut self, hint: &Name) -> Name {
+        // drop a numeric suffix so repeated renaming doesn't stack them up
+        let base = match hint.0.rsplit_once('_') {
+            Some((base, n)) if !base.is_empty() && n.chars().all(|c| c.is_ascii_digit()) => base,
+            _ => hint.0.as_str(),
+        };
+
+        let count = self.counts.entry(base.to_string()).or_insert(0);
+        loop {
+            *count += 1;
+            let name = Name(format!("{}_{}", base, count));
+            if self.taken.insert(name.clone()) {
+                return name;
+            }
+        }
+    }
 }
 
 impl<Lit> CntDef<Lit> {
@@ -429,3 +703,91 @@ impl<Lit: Display> Display for FunDef<Lit> {
         )
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use CpsExpr::*;
+
+    type Tree = CpsExpr<LitHigh>;
+
+    fn int(name: &str, value: i64, body: Tree) -> Tree {
+        Const {
+            name: Name::new(name),
+            value: LitHigh::Int(value.into()),
+            body: Box::new(body),
+        }
+    }
+
+    fn prim(name: &str, op: &str, args: &[&str], body: Tree) -> Tree {
+        Prim {
+            name: Name::new(name),
+            op: Name::new(op),
+            args: args.iter().map(|arg| Name::new(arg)).collect(),
+            loc: None,
+            body: Box::new(body),
+        }
+    }
+
+    // cnt `name`(`arg`) { halt(`arg`) } `name`(`value`)
+    fn jump(name: &str, arg: &str, value: &str) -> Tree {
+        Cnts {
+            cnts: vec![CntDef {
+                name: Name::new(name),
+                args: vec![Name::new(arg)],
+                body: Halt(Name::new(arg)),
+            }],
+            body: Box::new(AppC {
+                cnt: Name::new(name),
+                args: vec![Name::new(value)],
+            }),
+        }
+    }
+
+    fn halt(name: &str) -> Tree {
+        Halt(Name::new(name))
+    }
+
+    fn alpha_eq(l: &Tree, r: &Tree) -> bool {
+        assert_eq!(l.alpha_eq(r), r.alpha_eq(l), "alpha_eq is not symmetric");
+        l.alpha_eq(r)
+    }
+
+    #[test]
+    fn equal_up_to_renaming() {
+        let l = int("a", 1, prim("s", "+", &["a", "a"], jump("k", "v", "s")));
+        let r = int("b", 1, prim("t", "+", &["b", "b"], jump("j", "w", "t")));
+        assert!(alpha_eq(&l, &r));
+    }
+
+    #[test]
+    fn different_binding_structure() {
+        // the arguments are swapped
+        assert!(!alpha_eq(
+            &int("a", 1, int("b", 2, prim("c", "-", &["a", "b"], halt("c")))),
+            &int("a", 1, int("b", 2, prim("c", "-", &["b", "a"], halt("c")))),
+        ));
+        // free names must be the same, and not bound on one side only
+        assert!(!alpha_eq(&halt("x"), &halt("y")));
+        assert!(!alpha_eq(&int("a", 1, halt("b")), &int("b", 1, halt("b"))));
+        // nor can two binders stand for one
+        assert!(!alpha_eq(
+            &int("a", 1, int("b", 2, prim("c", "+", &["a", "b"], halt("c")))),
+            &int("a", 1, prim("c", "+", &["a", "a"], halt("c"))),
+        ));
+        assert!(!alpha_eq(&int("a", 1, halt("a")), &int("a", 2, halt("a"))));
+    }
+
+    #[test]
+    fn shadowing() {
+        // the inner binder is the one in scope
+        let shadowed = int("a", 1, int("a", 2, halt("a")));
+        assert!(alpha_eq(&shadowed, &int("x", 1, int("y", 2, halt("y")))));
+        assert!(!alpha_eq(&shadowed, &int("x", 1, int("y", 2, halt("x")))));
+        // a parameter shadows a name bound outside the continuation
+        assert!(alpha_eq(
+            &int("v", 1, jump("k", "v", "v")),
+            &int("a", 1, jump("k", "b", "a"))
+        ));
+    }
+}
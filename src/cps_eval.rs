@@ -0,0 +1,248 @@
+use std::{fmt, rc::Rc};
+
+use crate::{
+    ast::Name,
+    builtins::RuntimeError,
+    cps::{CntDef, CpsExpr, FunDef, LitHigh},
+    integer::Integer,
+    passes::eval_op,
+};
+
+// Bindings as a chain, so closures capture their environment cheaply
+#[derive(Clone)]
+pub struct Env<'a>(Option<Rc<(&'a Name, Value<'a>, Env<'a>)>>);
+
+impl<'a> Env<'a> {
+    fn bind(&self, name: &'a Name, value: Value<'a>) -> Self {
+        Env(Some(Rc::new((name, value, self.clone()))))
+    }
+
+    fn get(&self, name: &Name) -> Value<'a> {
+        let mut env = self;
+        while let Some(entry) = &env.0 {
+            if entry.0 == name {
+                return entry.1.clone();
+            }
+            env = &entry.2;
+        }
+
+        if name == &Name::new("halt") {
+            return Value::Halt;
+        }
+        panic!("Unbound name: {}", name)
+    }
+
+    // a recursive group sees itself
+    fn bind_funs(&self, funs: &'a [FunDef<LitHigh>]) -> Self {
+        let group = self.clone();
+        funs.iter().enumerate().fold(self.clone(), |env, (i, fun)| {
+            env.bind(&fun.name, Value::Fun(funs, i, group.clone()))
+        })
+    }
+
+    fn bind_cnts(&self, cnts: &'a [CntDef<LitHigh>]) -> Self {
+        let group = self.clone();
+        cnts.iter().enumerate().fold(self.clone(), |env, (i, cnt)| {
+            env.bind(&cnt.name, Value::Cnt(cnts, i, group.clone()))
+        })
+    }
+}
+
+#[derive(Clone)]
+pub enum Value<'a> {
+    Int(Integer),
+    Float(f64),
+    Str(Rc<str>),
+    // a descriptor followed by the fields
+    Block(Rc<Vec<Value<'a>>>),
+    // group of the definition, its index, and the environment of the group
+    Fun(&'a [FunDef<LitHigh>], usize, Env<'a>),
+    Cnt(&'a [CntDef<LitHigh>], usize, Env<'a>),
+    Halt,
+}
+
+impl fmt::Display for Value<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Value::Int(n) => write!(f, "{}", n),
+            Value::Float(x) => write!(f, "{}", x),
+            Value::Str(s) => write!(f, "{}", s),
+            Value::Block(vals) => {
+                write!(f, "data(")?;
+                for (i, val) in vals.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ", ")?;
+                    }
+                    write!(f, "{}", val)?;
+                }
+                write!(f, ")")
+            }
+            Value::Fun(..) => write!(f, "<function>"),
+            Value::Cnt(..) | Value::Halt => write!(f, "<continuation>"),
+        }
+    }
+}
+
+fn int(value: Value) -> Integer {
+    match value {
+        Value::Int(n) => n,
+        _ => panic!("Expected Int, got: {}", value),
+    }
+}
+
+fn lit(value: Value) -> LitHigh {
+    match value {
+        Value::Int(n) => LitHigh::Int(n),
+        Value::Float(x) => LitHigh::Float(x),
+        Value::Str(s) => LitHigh::Str(s.to_string()),
+        _ => panic!("Expected a literal, got: {}", value),
+    }
+}
+
+fn literal<'a>(lit: &LitHigh) -> Value<'a> {
+    match lit {
+        LitHigh::Int(n) => Value::Int(n.clone()),
+        LitHigh::Float(x) => Value::Float(*x),
+        LitHigh::Str(s) => Value::Str(s.as_str().into()),
+    }
+}
+
+fn block(value: Value) -> Rc<Vec<Value>> {
+    match value {
+        Value::Block(vals) => vals,
+        _ => panic!("Expected data block, got: {}", value),
+    }
+}
+
+fn prim<'a>(op: &Name, args: Vec<Value<'a>>) -> Result<Value<'a>, &'static str> {
+    match op.0.as_str() {
+        "id" => Ok(args[0].clone()),
+        "data" => Ok(Value::Block(Rc::new(args))),
+        "desc" => Ok(block(args[0].clone())[0].clone()),
+        "field" => {
+            let i = int(args[1].clone()).to_i64().unwrap() as usize;
+            Ok(block(args[0].clone())[i + 1].clone())
+        }
+        // unit is 0
+        "print" => {
+            println!("{}", args[0]);
+            Ok(Value::Int(0.into()))
+        }
+        _ => {
+            let args = args.into_iter().map(lit).collect::<Vec<_>>();
+            Ok(literal(&eval_op(op, &args)?))
+        }
+    }
+}
+
+/// Runs a CPS program to its `halt`, with the same runtime errors as the
+/// tree interpreter. Every call is a tail call, so this is a loop.
+pub fn eval(tree: &CpsExpr<LitHigh>) -> Result<Value<'_>, RuntimeError> {
+    use CpsExpr::*;
+
+    let mut tree = tree;
+    let mut env = Env(None);
+
+    loop {
+        match tree {
+            Const { name, value, body } => {
+                env = env.bind(name, literal(value));
+                tree = body;
+            }
+            Prim {
+                name,
+                op,
+                args,
+                loc,
+                body,
+            } => {
+                let args = args.iter().map(|arg| env.get(arg)).collect();
+                let value = prim(op, args).map_err(|message| RuntimeError {
+                    message: message.to_string(),
+                    loc: *loc,
+                })?;
+                env = env.bind(name, value);
+                tree = body;
+            }
+            Cnts { cnts, body } => {
+                env = env.bind_cnts(cnts);
+                tree = body;
+            }
+            Funs { funs, body } => {
+                env = env.bind_funs(funs);
+                tree = body;
+            }
+            AppC { cnt, args } => {
+                let args = args.iter().map(|arg| env.get(arg)).collect::<Vec<_>>();
+                match env.get(cnt) {
+                    Value::Cnt(cnts, i, group) => {
+                        let cnt = &cnts[i];
+                        env = cnt
+                            .args
+                            .iter()
+                            .zip(args)
+                            .fold(group.bind_cnts(cnts), |env, (name, arg)| {
+                                env.bind(name, arg)
+                            });
+                        tree = &cnt.body;
+                    }
+                    Value::Halt if args.is_empty() => {
+                        return Err(RuntimeError {
+                            message: "pattern match failed".to_string(),
+                            loc: None,
+                        })
+                    }
+                    Value::Halt => return Ok(args[0].clone()),
+                    value => panic!("{} is not a continuation", value),
+                }
+            }
+            AppF { fun, ret, args } => {
+                let ret = env.get(ret);
+                let args = args.iter().map(|arg| env.get(arg)).collect::<Vec<_>>();
+                match env.get(fun) {
+                    Value::Fun(funs, i, group) => {
+                        let fun = &funs[i];
+                        env = fun
+                            .args
+                            .iter()
+                            .zip(args)
+                            .fold(group.bind_funs(funs), |env, (name, arg)| {
+                                env.bind(name, arg)
+                            })
+                            .bind(&fun.ret, ret);
+                        tree = &fun.body;
+                    }
+                    value => panic!("{} is not callable", value),
+                }
+            }
+            If { op, args, t, f } => {
+                let args = args.iter().map(|arg| env.get(arg)).collect();
+                let taken = match prim(op, args) {
+                    Ok(Value::Int(n)) if n.is_positive() => t,
+                    Ok(_) => f,
+                    Err(message) => {
+                        return Err(RuntimeError {
+                            message: message.to_string(),
+                            loc: None,
+                        })
+                    }
+                };
+
+                match env.get(taken) {
+                    Value::Cnt(cnts, i, group) => {
+                        env = group.bind_cnts(cnts);
+                        tree = &cnts[i].body;
+                    }
+                    Value::Halt => {
+                        return Err(RuntimeError {
+                            message: "pattern match failed".to_string(),
+                            loc: None,
+                        })
+                    }
+                    value => panic!("{} is not a continuation", value),
+                }
+            }
+            Halt(name) => return Ok(env.get(name)),
+        }
+    }
+}
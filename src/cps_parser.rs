@@ -0,0 +1,543 @@
+//! Reads back the textual CPS syntax that `Display` for `CpsExpr` prints.
+//!
+//! ```text
+//! expr ::= "const" name "=" (["-"] number | string) ";" expr
+//!        | "let" name "=" prim ";" expr
+//!        | def+ expr
+//!        | "if" "(" prim ")" "{" name "(" ")" "}" "else" "{" name "(" ")" "}"
+//!        | name "(" names ")"
+//! prim ::= name binop name | unop name | name "(" names ")"
+//! def  ::= "cnt" name "(" names ")" "{" expr "}"
+//!        | "function" name "(" name "," names ")" "{" expr "}"
+//! ```
+//!
+//! `binop` is one of `+ - * / % == != < > <= >= && ||` and `unop` one of `! ~`.
+//! Names in a list are separated by commas, a trailing comma is allowed, and the
+//! arguments of an `if` test written as `op(a b)` may also be separated by spaces.
+//! `//` and `/* */` comments are skipped.
+//!
+//! Consecutive definitions of the same kind form one group, so they can refer
+//! to each other. A call `k(a, b)` jumps to `k` when `k` is a continuation in
+//! scope (bound by `cnt` or as the first parameter of a `function`), and
+//! otherwise calls the function `k` with `a` as its return continuation.
+//! `halt(x)` ends the program with `x`, while `halt()` is the jump the match
+//! lowering uses when no arm applies.
+
+use logos::{Lexer, Logos};
+use std::collections::HashSet;
+use std::iter::Peekable;
+
+use crate::{
+    ast::{Loc, Name},
+    cps::{arith_op, traps, CntDef, CpsExpr as BaseCpsExpr, FunDef, LitHigh},
+    integer::Integer,
+    lexer::unescape,
+};
+
+type CpsExpr = BaseCpsExpr<LitHigh>;
+
+#[derive(Logos, Clone, Debug, PartialEq)]
+#[logos(skip r"[ \t\n\f]+")]
+pub enum CpsToken {
+    // keywords
+    #[token("const")]
+    Const,
+    #[token("let")]
+    Let,
+    #[token("cnt")]
+    Cnt,
+    #[token("function")]
+    Function,
+    #[token("if")]
+    If,
+    #[token("else")]
+    Else,
+
+    // big ones may carry the `n` suffix of JS bigints
+    #[regex("[0-9]+n?", |lex| lex.slice().trim_end_matches('n').parse::<Integer>().unwrap())]
+    Int(Integer),
+
+    #[regex(r"[0-9]+\.[0-9]+([eE][-+]?[0-9]+)?", |lex| lex.slice().parse::<f64>().unwrap())]
+    #[regex(r"[0-9]+[eE][-+]?[0-9]+", |lex| lex.slice().parse::<f64>().unwrap())]
+    #[token("Infinity", |_| f64::INFINITY)]
+    #[token("NaN", |_| f64::NAN)]
+    Float(f64),
+
+    #[regex("==|!=|<=|>=|&&|\\|\\||[-+*/%~!<>]", |lex| lex.slice().to_string())]
+    Op(String),
+
+    #[token("=")]
+    Eq,
+    #[token(";")]
+    Semi,
+    #[token(",")]
+    Comma,
+    #[token("(")]
+    POpen,
+    #[token(")")]
+    PClose,
+    #[token("{")]
+    BOpen,
+    #[token("}")]
+    BClose,
+
+    #[regex("[a-zA-Z_][a-zA-Z0-9_]*", |lex| lex.slice().to_string())]
+    Ident(String),
+
+    // a literal, or the source location "line:col" of a checked operation
+    #[regex(r#""([^"\\]|\\.)*""#, |lex| unescape(&lex.slice()[1..lex.slice().len() - 1]))]
+    Str(String),
+
+    // comments
+    #[regex("//[^\n]*", logos::skip)]
+    #[regex("/\\*([^*]|\\*+[^*/])*\\*+/", logos::skip)]
+    Comment,
+
+    Eof,
+}
+
+pub struct CpsParser<'a> {
+    lexer: Peekable<Lexer<'a, CpsToken>>,
+}
+
+type Result<T> = std::result::Result<T, String>;
+
+pub fn parse(text: &str) -> Result<CpsExpr> {
+    let mut parser = CpsParser::new(CpsToken::lexer(text));
+    let tree = parser.parse_expr()?;
+    parser.expect(CpsToken::Eof)?;
+
+    resolve_calls(tree, &HashSet::new())
+}
+
+fn parse_loc(text: &str) -> Result<Loc> {
+    let at = text.split_once(':').and_then(|(line, col)| {
+        Some(Loc {
+            line: line.parse().ok()?,
+            col: col.parse().ok()?,
+        })
+    });
+    at.ok_or_else(|| format!("Expected source location \"line:col\", got {:?}", text))
+}
+
+impl<'a> CpsParser<'a> {
+    pub fn new(lexer: Lexer<'a, CpsToken>) -> Self {
+        CpsParser {
+            lexer: lexer.peekable(),
+        }
+    }
+
+    fn peek(&mut self) -> Result<&CpsToken> {
+        match self.lexer.peek() {
+            Some(Ok(token)) => Ok(token),
+            Some(Err(_)) => Err("Unexpected character in CPS input".to_string()),
+            None => Ok(&CpsToken::Eof),
+        }
+    }
+
+    fn accept(&mut self) -> Result<CpsToken> {
+        match self.lexer.next() {
+            Some(Ok(token)) => Ok(token),
+            Some(Err(_)) => Err("Unexpected character in CPS input".to_string()),
+            None => Ok(CpsToken::Eof),
+        }
+    }
+
+    fn expect(&mut self, token: CpsToken) -> Result<()> {
+        let next = self.accept()?;
+
+        if next != token {
+            return Err(format!("Expected {:?}, got {:?}", token, next));
+        }
+        Ok(())
+    }
+
+    fn expect_name(&mut self) -> Result<Name> {
+        match self.accept()? {
+            CpsToken::Ident(name) => Ok(Name(name)),
+            token => Err(format!("Expected name, got {:?}", token)),
+        }
+    }
+
+    // names up to the closing parenthesis, separated by commas (or spaces)
+    fn parse_names(&mut self) -> Result<Vec<Name>> {
+        let mut names = vec![];
+        loop {
+            match self.peek()? {
+                CpsToken::PClose => break,
+                CpsToken::Comma => {
+                    self.accept()?;
+                }
+                _ => names.push(self.expect_name()?),
+            }
+        }
+        self.expect(CpsToken::PClose)?;
+
+        Ok(names)
+    }
+
+    fn parse_prim(&mut self) -> Result<(Name, Vec<Name>, Option<Loc>)> {
+        if let CpsToken::Op(op) = self.peek()?.clone() {
+            self.accept()?;
+            return Ok((Name(op), vec![self.expect_name()?], None));
+        }
+
+        let lhs = self.expect_name()?;
+        match self.accept()? {
+            CpsToken::Op(op) => Ok((Name(op), vec![lhs, self.expect_name()?], None)),
+            CpsToken::POpen => {
+                // ops that trap may end with their source location
+                let op = arith_op(&lhs.0).unwrap_or(lhs);
+                let checked = traps(&op);
+
+                let mut args = vec![];
+                let mut loc = None;
+                loop {
+                    match self.accept()? {
+                        CpsToken::PClose => break,
+                        CpsToken::Comma => {}
+                        CpsToken::Ident(name) if loc.is_none() => args.push(Name(name)),
+                        CpsToken::Str(at) if checked && loc.is_none() => {
+                            loc = Some(parse_loc(&at)?)
+                        }
+                        token => {
+                            return Err(format!("Unexpected {:?} in arguments of {}", token, op))
+                        }
+                    }
+                }
+
+                Ok((op, args, loc))
+            }
+            token => Err(format!("Expected operator or '(', got {:?}", token)),
+        }
+    }
+
+    fn parse_jump(&mut self) -> Result<Name> {
+        self.expect(CpsToken::BOpen)?;
+        let cnt = self.expect_name()?;
+        self.expect(CpsToken::POpen)?;
+        self.expect(CpsToken::PClose)?;
+        self.expect(CpsToken::BClose)?;
+
+        Ok(cnt)
+    }
+
+    fn parse_body(&mut self) -> Result<CpsExpr> {
+        self.expect(CpsToken::BOpen)?;
+        let body = self.parse_expr()?;
+        self.expect(CpsToken::BClose)?;
+
+        Ok(body)
+    }
+
+    fn parse_cnt(&mut self) -> Result<CntDef<LitHigh>> {
+        self.expect(CpsToken::Cnt)?;
+        let name = self.expect_name()?;
+        self.expect(CpsToken::POpen)?;
+        let args = self.parse_names()?;
+
+        Ok(CntDef {
+            name,
+            args,
+            body: self.parse_body()?,
+        })
+    }
+
+    fn parse_fun(&mut self) -> Result<FunDef<LitHigh>> {
+        self.expect(CpsToken::Function)?;
+        let name = self.expect_name()?;
+        self.expect(CpsToken::POpen)?;
+        let mut args = self.parse_names()?;
+        if args.is_empty() {
+            return Err(format!("Function {} has no return continuation", name));
+        }
+        let ret = args.remove(0);
+
+        Ok(FunDef {
+            name,
+            ret,
+            args,
+            body: self.parse_body()?,
+        })
+    }
+
+    pub fn parse_expr(&mut self) -> Result<CpsExpr> {
+        let tree = match self.peek()? {
+            CpsToken::Const => {
+                self.accept()?;
+                let name = self.expect_name()?;
+                self.expect(CpsToken::Eq)?;
+
+                let negative = *self.peek()? == CpsToken::Op("-".to_string());
+                if negative {
+                    self.accept()?;
+                }
+                let value = match self.accept()? {
+                    CpsToken::Int(n) if negative => LitHigh::Int(n.neg()),
+                    CpsToken::Int(n) => LitHigh::Int(n),
+                    CpsToken::Float(x) if negative => LitHigh::Float(-x),
+                    CpsToken::Float(x) => LitHigh::Float(x),
+                    CpsToken::Str(s) if !negative => LitHigh::Str(s),
+                    token => return Err(format!("Expected literal, got {:?}", token)),
+                };
+                self.expect(CpsToken::Semi)?;
+
+                CpsExpr::Const {
+                    name,
+                    value,
+                    body: Box::new(self.parse_expr()?),
+                }
+            }
+            CpsToken::Let => {
+                self.accept()?;
+                let name = self.expect_name()?;
+                self.expect(CpsToken::Eq)?;
+                let (op, args, loc) = self.parse_prim()?;
+                self.expect(CpsToken::Semi)?;
+
+                CpsExpr::Prim {
+                    name,
+                    op,
+                    args,
+                    loc,
+                    body: Box::new(self.parse_expr()?),
+                }
+            }
+            CpsToken::Cnt => {
+                let mut cnts = vec![];
+                while *self.peek()? == CpsToken::Cnt {
+                    cnts.push(self.parse_cnt()?);
+                }
+
+                CpsExpr::Cnts {
+                    cnts,
+                    body: Box::new(self.parse_expr()?),
+                }
+            }
+            CpsToken::Function => {
+                let mut funs = vec![];
+                while *self.peek()? == CpsToken::Function {
+                    funs.push(self.parse_fun()?);
+                }
+
+                CpsExpr::Funs {
+                    funs,
+                    body: Box::new(self.parse_expr()?),
+                }
+            }
+            CpsToken::If => {
+                self.accept()?;
+                self.expect(CpsToken::POpen)?;
+                let (op, args, _) = self.parse_prim()?;
+                self.expect(CpsToken::PClose)?;
+                let t = self.parse_jump()?;
+                self.expect(CpsToken::Else)?;
+                let f = self.parse_jump()?;
+
+                CpsExpr::If { op, args, t, f }
+            }
+            CpsToken::Ident(_) => {
+                let target = self.expect_name()?;
+                self.expect(CpsToken::POpen)?;
+
+                // jump or call, decided by resolve_calls once scopes are known
+                CpsExpr::AppC {
+                    cnt: target,
+                    args: self.parse_names()?,
+                }
+            }
+            token => return Err(format!("Expected CPS expression, got {:?}", token)),
+        };
+
+        Ok(tree)
+    }
+}
+
+// Turns every parsed call whose target is not a continuation in scope into a
+// function call
+fn resolve_calls(tree: CpsExpr, cnts: &HashSet<Name>) -> Result<CpsExpr> {
+    use BaseCpsExpr::*;
+    let tree = match tree {
+        Const { name, value, body } => Const {
+            name,
+            value,
+            body: Box::new(resolve_calls(*body, cnts)?),
+        },
+        Prim {
+            name,
+            op,
+            args,
+            loc,
+            body,
+        } => Prim {
+            name,
+            op,
+            args,
+            loc,
+            body: Box::new(resolve_calls(*body, cnts)?),
+        },
+        Cnts { cnts: defs, body } => {
+            let mut cnts = cnts.clone();
+            cnts.extend(defs.iter().map(|cnt| cnt.name.clone()));
+
+            let defs = defs
+                .into_iter()
+                .map(|cnt| {
+                    let scope = &cnts - &cnt.args.iter().cloned().collect();
+                    Ok(CntDef {
+                        body: resolve_calls(cnt.body, &scope)?,
+                        ..cnt
+                    })
+                })
+                .collect::<Result<_>>()?;
+
+            Cnts {
+                cnts: defs,
+                body: Box::new(resolve_calls(*body, &cnts)?),
+            }
+        }
+        Funs { funs, body } => {
+            let funs = funs
+                .into_iter()
+                .map(|fun| {
+                    Ok(FunDef {
+                        // continuations of the enclosing function are out of reach
+                        body: resolve_calls(fun.body, &HashSet::from([fun.ret.clone()]))?,
+                        ..fun
+                    })
+                })
+                .collect::<Result<_>>()?;
+
+            Funs {
+                funs,
+                body: Box::new(resolve_calls(*body, cnts)?),
+            }
+        }
+        AppC { cnt, mut args } => {
+            if cnts.contains(&cnt) {
+                AppC { cnt, args }
+            } else if cnt == Name::new("halt") {
+                match args.len() {
+                    0 => AppC { cnt, args },
+                    1 => Halt(args.remove(0)),
+                    _ => {
+                        let msg = format!("halt takes at most one argument, given {}", args.len());
+                        return Err(msg);
+                    }
+                }
+            } else if args.is_empty() {
+                return Err(format!("Call to {} has no return continuation", cnt));
+            } else {
+                let ret = args.remove(0);
+                AppF {
+                    fun: cnt,
+                    ret,
+                    args,
+                }
+            }
+        }
+        AppF { .. } | If { .. } | Halt(_) => tree,
+    };
+
+    Ok(tree)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cps_eval;
+
+    // Reads back the printed tree, which prints the same and computes the
+    // same. Nested groups of definitions print as one group, so the tree read
+    // back can be grouped differently.
+    fn round_trip(tree: &CpsExpr) -> CpsExpr {
+        let text = format!("{:#}", tree);
+        let back = parse(&text).unwrap_or_else(|err| panic!("{}, in\n{}", err, text));
+        assert_eq!(text, format!("{:#}", back));
+
+        let eval = |tree| match cps_eval::eval(tree) {
+            Ok(value) => value.to_string(),
+            Err(err) => err.to_string(),
+        };
+        assert_eq!(eval(tree), eval(&back));
+        back
+    }
+
+    #[test]
+    fn round_trip_text() {
+        let tree = parse(
+            r#"
+                const a = -3;
+                const b = 2.5e10;
+                const s = "a \"quoted\"\n string";
+                let c = a + a;
+                let d = div(c, a, "3:7");
+                let e = ! d;
+                function f(r, x, y) {
+                    cnt k(v) { r(v) }
+                    cnt l() { k(x) }
+                    if (x < y) { l() } else { l() }
+                }
+                function g(r) { f(r, a, c) }
+                cnt done(x) { halt(x) }
+                g(done)
+                "#,
+        )
+        .unwrap();
+        assert!(tree.alpha_eq(&round_trip(&tree)));
+    }
+
+    // everything the front end lowers to reads back the same
+    #[test]
+    fn round_trip_lowered() {
+        use crate::{ast_to_cps::AstToCps, checker::TypeChecker, lexer::Token, parser::Parser};
+
+        let source = "
+            data List = Nil | Cons(Int, List)
+            fn sum(l: List): Int = match l
+                | Nil => 0
+                | Cons(x, rest) if x > 0 => x + sum(rest)
+                | Cons(_, rest) => sum(rest)
+            let s = concat(\"x\", \"y\")
+            let f = to_float(3) * -1.5
+            sum(Cons(-1, Cons(2, Nil))) / length(s) + truncate(f)
+        ";
+        let program = Parser::new(Token::lexer(source)).parse_program();
+        let program = TypeChecker::new().infer(program);
+        round_trip(&AstToCps::convert(program));
+    }
+
+    #[test]
+    fn resolves_jumps_and_calls() {
+        let tree = parse("function f(r, x) { r(x) } cnt k(x) { halt(x) } const a = 1; f(k, a)");
+        let BaseCpsExpr::Funs { funs, body } = tree.unwrap() else {
+            panic!("expected functions");
+        };
+        assert!(matches!(funs[0].body, BaseCpsExpr::AppC { .. }));
+        let BaseCpsExpr::Cnts { cnts, body } = *body else {
+            panic!("expected continuations");
+        };
+        assert!(matches!(cnts[0].body, BaseCpsExpr::Halt(_)));
+        assert!(
+            matches!(*body, BaseCpsExpr::Const { body, .. } if matches!(*body, BaseCpsExpr::AppF { .. }))
+        );
+    }
+
+    #[test]
+    fn errors() {
+        let error = |text| parse(text).unwrap_err();
+
+        assert_eq!(error("const a = ;"), "Expected literal, got Semi");
+        assert_eq!(error("halt(a) }"), "Expected Eof, got BClose");
+        assert_eq!(error("let a = b ? c;"), "Unexpected character in CPS input");
+        assert_eq!(error("f()"), "Call to f has no return continuation");
+        assert_eq!(
+            error("function f() { halt() } halt()"),
+            "Function f has no return continuation"
+        );
+        assert_eq!(
+            error("let a = div(b, c, \"here\"); halt(a)"),
+            "Expected source location \"line:col\", got \"here\""
+        );
+    }
+}
@@ -12,6 +12,9 @@ impl fmt::Display for Type {
         use Type::*;
         match self {
             Int => write!(f, "Int"),
+            Float => write!(f, "Float"),
+            Str => write!(f, "String"),
+            Char => write!(f, "Char"),
             Bool => write!(f, "Bool"),
             Unit => write!(f, "Unit"),
             Fn(args, ret) => {
@@ -24,7 +27,17 @@ impl fmt::Display for Type {
                 }
                 write!(f, ") -> {}", ret)
             }
-            UserDef(name) => write!(f, "{}", name.0),
+            Tuple(types) => {
+                write!(f, "(")?;
+                for (i, ty) in types.iter().enumerate() {
+                    write!(f, "{}", ty)?;
+                    if i < types.len() - 1 {
+                        write!(f, ", ")?;
+                    }
+                }
+                write!(f, ")")
+            }
+            UserDef(name) | Alias(name, _) => write!(f, "{}", name.0),
             TyVar(i) => write!(f, "T{}", i),
         }
     }
@@ -51,7 +64,16 @@ impl fmt::Display for DataDef {
 
 impl fmt::Display for Cons {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        if !self.args.is_empty() {
+        if !self.fields.is_empty() {
+            write!(f, " {{ ")?;
+            for (i, (name, arg)) in self.fields.iter().zip(&self.args).enumerate() {
+                write!(f, "{}: {}", name, arg)?;
+                if i < self.args.len() - 1 {
+                    write!(f, ", ")?;
+                }
+            }
+            write!(f, " }}")?;
+        } else if !self.args.is_empty() {
             write!(f, "(")?;
             for (i, arg) in self.args.iter().enumerate() {
                 write!(f, "{}", arg)?;
@@ -71,6 +93,7 @@ impl fmt::Display for Pattern {
         match self {
             Var(name, ty) => write!(f, "({name}: {ty})"),
             Int(n) => write!(f, "{}", n),
+            Char(c) => write!(f, "{:?}", c),
             Bool(b) => write!(f, "{}", b),
             Data(_, name, pats) => {
                 write!(f, "{}(", name)?;
@@ -82,6 +105,27 @@ impl fmt::Display for Pattern {
                 }
                 write!(f, ")")
             }
+            Tuple(pats) => {
+                write!(f, "(")?;
+                for (i, pat) in pats.iter().enumerate() {
+                    write!(f, "{}", pat)?;
+                    if i < pats.len() - 1 {
+                        write!(f, ", ")?;
+                    }
+                }
+                write!(f, ")")
+            }
+            Wildcard => write!(f, "_"),
+            Or(alts) => {
+                for (i, alt) in alts.iter().enumerate() {
+                    write!(f, "{}", alt)?;
+                    if i < alts.len() - 1 {
+                        write!(f, " | ")?;
+                    }
+                }
+                Ok(())
+            }
+            As(name, _, pat) => write!(f, "{} @ {}", name, pat),
         }
     }
 }
@@ -95,6 +139,7 @@ impl fmt::Display for Expr {
                 write!(f, "{}\n", fn_def)?;
                 write!(f, "{}", expr)
             }
+            Seq(simp, _, expr) => write!(f, "{};\n{}", simp, expr),
             Simp(simp) => write!(f, "{}", simp),
         }
     }
@@ -106,15 +151,18 @@ impl fmt::Display for Simp {
         match self {
             Match(expr, arms) => {
                 write!(f, "match {} {{\n", expr)?;
-                for (i, (pat, simp)) in arms.iter().enumerate() {
-                    write!(f, "{} => {}", pat, simp)?;
+                for (i, (pat, guard, simp)) in arms.iter().enumerate() {
+                    match guard {
+                        Some(guard) => write!(f, "{} if {} => {}", pat, guard, simp)?,
+                        None => write!(f, "{} => {}", pat, simp)?,
+                    }
                     if i < arms.len() - 1 {
                         write!(f, "\n")?;
                     }
                 }
                 write!(f, "}}")
             }
-            FnCall(fn_name, args) => {
+            FnCall(fn_name, args, _) => {
                 write!(f, "{}(", fn_name)?;
                 for (i, arg) in args.iter().enumerate() {
                     write!(f, "{}", arg)?;
@@ -127,6 +175,9 @@ impl fmt::Display for Simp {
             Block(expr) => write!(f, "{{\n{}\n}}", expr),
             Ref(name) => write!(f, "({})", name),
             Int(n) => write!(f, "{}", n),
+            Float(x) => write!(f, "{:?}", x),
+            Str(s) => write!(f, "{:?}", s),
+            Char(c) => write!(f, "{:?}", c),
             Bool(b) => write!(f, "{}", b),
             Unit => write!(f, "()"),
             Data(name, args) => {
@@ -143,6 +194,27 @@ impl fmt::Display for Simp {
                     write!(f, ")")
                 }
             }
+            Tuple(args) => {
+                write!(f, "(")?;
+                for (i, arg) in args.iter().enumerate() {
+                    write!(f, "{}", arg)?;
+                    if i < args.len() - 1 {
+                        write!(f, ", ")?;
+                    }
+                }
+                write!(f, ")")
+            }
+            Field(simp, name, _, _) => write!(f, "{}.{}", simp, name),
+            Update(simp, fields, _, _) => {
+                write!(f, "{{ {} with ", simp)?;
+                for (i, (name, arg)) in fields.iter().enumerate() {
+                    write!(f, "{} = {}", name, arg)?;
+                    if i < fields.len() - 1 {
+                        write!(f, ", ")?;
+                    }
+                }
+                write!(f, " }}")
+            }
         }
     }
 }
@@ -169,12 +241,18 @@ impl fmt::Display for Program {
         for data_def in self.data_defs.iter() {
             write!(f, "{}\n", data_def)?;
         }
-        // for type_def in self.type_defs.iter() {
-        //     write!(f, "{}\n", type_def)?;
-        // }
+        for type_def in self.type_defs.iter() {
+            writeln!(f, "{}", type_def)?;
+        }
         if let Some(expr) = &self.expr {
             write!(f, "{}", expr)?;
         }
         Ok(())
     }
 }
+
+impl fmt::Display for Loc {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}:{}", self.line, self.col)
+    }
+}
@@ -0,0 +1,191 @@
+use num_bigint::BigInt;
+use num_traits::{FromPrimitive, ToPrimitive};
+use std::{cmp::Ordering, fmt, rc::Rc, str::FromStr};
+
+/// The value of an `Int`. Results that fit in a machine word stay `Small` and
+/// take the fast path; arithmetic that would overflow moves to `Big`. Values
+/// are always stored in the smallest form, so equal integers compare equal.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum Integer {
+    Small(i64),
+    Big(Rc<BigInt>),
+}
+
+use Integer::*;
+
+impl From<i64> for Integer {
+    fn from(n: i64) -> Self {
+        Small(n)
+    }
+}
+
+impl From<BigInt> for Integer {
+    fn from(n: BigInt) -> Self {
+        match i64::try_from(&n) {
+            Ok(n) => Small(n),
+            Err(_) => Big(Rc::new(n)),
+        }
+    }
+}
+
+impl FromStr for Integer {
+    type Err = num_bigint::ParseBigIntError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.parse::<i64>() {
+            Ok(n) => Ok(Small(n)),
+            Err(_) => Ok(s.parse::<BigInt>()?.into()),
+        }
+    }
+}
+
+impl fmt::Display for Integer {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Small(n) => write!(f, "{}", n),
+            Big(n) => write!(f, "{}", n),
+        }
+    }
+}
+
+impl PartialOrd for Integer {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Integer {
+    fn cmp(&self, other: &Self) -> Ordering {
+        match (self, other) {
+            (Small(x), Small(y)) => x.cmp(y),
+            _ => self.big().cmp(&other.big()),
+        }
+    }
+}
+
+// Largest shift amount; anything beyond would not fit in memory anyway
+const MAX_SHIFT: i64 = u16::MAX as i64;
+
+impl Integer {
+    pub fn to_i64(&self) -> Option<i64> {
+        match self {
+            Small(n) => Some(*n),
+            Big(_) => None,
+        }
+    }
+
+    // the nearest float
+    pub fn to_f64(&self) -> f64 {
+        match self {
+            Small(n) => *n as f64,
+            Big(n) => n.to_f64().unwrap(),
+        }
+    }
+
+    // the integer part of a float, dropping the fraction
+    pub fn truncate(x: f64) -> Result<Self, &'static str> {
+        match BigInt::from_f64(x.trunc()) {
+            Some(n) => Ok(n.into()),
+            None => Err("float is not finite"),
+        }
+    }
+
+    // how tests read an int, as with `if`
+    pub fn is_positive(&self) -> bool {
+        *self > Small(0)
+    }
+
+    fn big(&self) -> BigInt {
+        match self {
+            Small(n) => BigInt::from(*n),
+            Big(n) => n.as_ref().clone(),
+        }
+    }
+
+    // the machine op when it doesn't overflow, the bignum one otherwise
+    fn arith(
+        &self,
+        other: &Self,
+        small: fn(i64, i64) -> Option<i64>,
+        big: fn(BigInt, BigInt) -> BigInt,
+    ) -> Self {
+        if let (Small(x), Small(y)) = (self, other) {
+            if let Some(n) = small(*x, *y) {
+                return Small(n);
+            }
+        }
+        big(self.big(), other.big()).into()
+    }
+
+    pub fn add(&self, other: &Self) -> Self {
+        self.arith(other, i64::checked_add, |x, y| x + y)
+    }
+
+    pub fn sub(&self, other: &Self) -> Self {
+        self.arith(other, i64::checked_sub, |x, y| x - y)
+    }
+
+    pub fn mul(&self, other: &Self) -> Self {
+        self.arith(other, i64::checked_mul, |x, y| x * y)
+    }
+
+    pub fn neg(&self) -> Self {
+        Small(0).sub(self)
+    }
+
+    // Both round toward zero, so the remainder has the sign of the dividend
+    pub fn div(&self, other: &Self) -> Result<Self, &'static str> {
+        if *other == Small(0) {
+            return Err("division by zero");
+        }
+        Ok(self.arith(other, i64::checked_div, |x, y| x / y))
+    }
+
+    pub fn rem(&self, other: &Self) -> Result<Self, &'static str> {
+        if *other == Small(0) {
+            return Err("division by zero");
+        }
+        Ok(self.arith(other, i64::checked_rem, |x, y| x % y))
+    }
+
+    fn shift_amount(&self) -> Result<usize, &'static str> {
+        match self {
+            Small(k) if (0..=MAX_SHIFT).contains(k) => Ok(*k as usize),
+            _ => Err("shift out of range"),
+        }
+    }
+
+    // multiplies by 2^k
+    pub fn shl(&self, k: &Self) -> Result<Self, &'static str> {
+        let k = k.shift_amount()?;
+        let pow2 = (k < 63).then(|| 1i64 << k);
+        match (self, pow2) {
+            (Small(x), Some(pow2)) if x.checked_mul(pow2).is_some() => Ok(Small(x * pow2)),
+            _ => Ok((self.big() << k).into()),
+        }
+    }
+
+    // divides by 2^k, rounding toward zero like `/`
+    pub fn shr(&self, k: &Self) -> Result<Self, &'static str> {
+        let k = k.shift_amount()?;
+        match self {
+            Small(x) if k < 63 => Ok(Small(x / (1i64 << k))),
+            _ => Ok((self.big() / (BigInt::from(1) << k)).into()),
+        }
+    }
+
+    pub fn not(&self) -> Self {
+        match self {
+            Small(n) => Small(!n),
+            Big(n) => (!n.as_ref()).into(),
+        }
+    }
+
+    pub fn and(&self, other: &Self) -> Self {
+        self.arith(other, |x, y| Some(x & y), |x, y| x & y)
+    }
+
+    pub fn or(&self, other: &Self) -> Self {
+        self.arith(other, |x, y| Some(x | y), |x, y| x | y)
+    }
+}
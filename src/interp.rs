@@ -1,16 +1,21 @@
 use crate::ast::*;
 use crate::builtins::*;
+use crate::integer::Integer;
 use core::fmt;
 use std::{cell::RefCell, collections::HashMap, rc::Rc};
 
-type BuiltInFn = fn(Vec<Value>) -> Value;
+type BuiltInFn = fn(Vec<Value>) -> Result<Value, &'static str>;
 
 #[derive(Debug, Clone)]
 pub enum Value {
-    Int(i64),
+    Int(Integer),
+    Float(f64),
+    Str(String),
+    Char(char),
     Bool(bool),
     Unit,
     Data(Name, Vec<Value>),
+    Tuple(Vec<Value>),
     Closure(Env, Rc<FnDef>),
     BuiltIn(BuiltInFn),
 }
@@ -20,6 +25,9 @@ impl fmt::Display for Value {
         use Value::*;
         match self {
             Int(n) => write!(f, "{}", n),
+            Float(x) => write!(f, "{}", x),
+            Str(s) => write!(f, "{}", s),
+            Char(c) => write!(f, "{}", c),
             Bool(b) => write!(f, "{}", b),
             Unit => write!(f, "()"),
             Data(name, args) => {
@@ -32,6 +40,16 @@ impl fmt::Display for Value {
                 }
                 write!(f, ")")
             }
+            Tuple(vals) => {
+                write!(f, "(")?;
+                for (i, val) in vals.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ", ")?;
+                    }
+                    write!(f, "{}", val)?;
+                }
+                write!(f, ")")
+            }
             Closure(_, _) => write!(f, "<closure>"),
             BuiltIn(_) => write!(f, "<builtin>"),
         }
@@ -122,6 +140,86 @@ impl Env {
                     Name(String::from("!")),
                     Rc::new(RefCell::new(Some(Value::BuiltIn(not)))),
                 ),
+                (
+                    Name(String::from("+.")),
+                    Rc::new(RefCell::new(Some(Value::BuiltIn(fadd)))),
+                ),
+                (
+                    Name(String::from("-.")),
+                    Rc::new(RefCell::new(Some(Value::BuiltIn(fsub)))),
+                ),
+                (
+                    Name(String::from("*.")),
+                    Rc::new(RefCell::new(Some(Value::BuiltIn(fmul)))),
+                ),
+                (
+                    Name(String::from("/.")),
+                    Rc::new(RefCell::new(Some(Value::BuiltIn(fdiv)))),
+                ),
+                (
+                    Name(String::from("==.")),
+                    Rc::new(RefCell::new(Some(Value::BuiltIn(feq)))),
+                ),
+                (
+                    Name(String::from("!=.")),
+                    Rc::new(RefCell::new(Some(Value::BuiltIn(fneq)))),
+                ),
+                (
+                    Name(String::from("<.")),
+                    Rc::new(RefCell::new(Some(Value::BuiltIn(flt)))),
+                ),
+                (
+                    Name(String::from(">.")),
+                    Rc::new(RefCell::new(Some(Value::BuiltIn(fgt)))),
+                ),
+                (
+                    Name(String::from("<=.")),
+                    Rc::new(RefCell::new(Some(Value::BuiltIn(fle)))),
+                ),
+                (
+                    Name(String::from(">=.")),
+                    Rc::new(RefCell::new(Some(Value::BuiltIn(fge)))),
+                ),
+                (
+                    Name(String::from("to_float")),
+                    Rc::new(RefCell::new(Some(Value::BuiltIn(to_float)))),
+                ),
+                (
+                    Name(String::from("truncate")),
+                    Rc::new(RefCell::new(Some(Value::BuiltIn(truncate)))),
+                ),
+                (
+                    Name(String::from("concat")),
+                    Rc::new(RefCell::new(Some(Value::BuiltIn(concat)))),
+                ),
+                (
+                    Name(String::from("length")),
+                    Rc::new(RefCell::new(Some(Value::BuiltIn(length)))),
+                ),
+                (
+                    Name(String::from("char_at")),
+                    Rc::new(RefCell::new(Some(Value::BuiltIn(char_at)))),
+                ),
+                (
+                    Name(String::from("substring")),
+                    Rc::new(RefCell::new(Some(Value::BuiltIn(substring)))),
+                ),
+                (
+                    Name(String::from("compare")),
+                    Rc::new(RefCell::new(Some(Value::BuiltIn(compare)))),
+                ),
+                (
+                    Name(String::from("int_to_string")),
+                    Rc::new(RefCell::new(Some(Value::BuiltIn(int_to_string)))),
+                ),
+                (
+                    Name(String::from("string_to_int")),
+                    Rc::new(RefCell::new(Some(Value::BuiltIn(string_to_int)))),
+                ),
+                (
+                    Name(String::from("print")),
+                    Rc::new(RefCell::new(Some(Value::BuiltIn(print)))),
+                ),
             ]),
         }
     }
@@ -187,12 +285,12 @@ fn free_vars_expr(expr: &Expr) -> Vec<Name> {
                 .iter()
                 .map(|(name, _)| name.clone())
                 .collect::<Vec<_>>();
+            let nbound = f.name.clone();
             let fun_free = free_vars_simp(&f.body)
                 .into_iter()
-                .filter(|name| !args.contains(name));
+                .filter(|name| !args.contains(name) && name != &nbound);
 
             let body_free = free_vars_expr(body);
-            let nbound = f.name.clone();
 
             body_free
                 .into_iter()
@@ -200,6 +298,10 @@ fn free_vars_expr(expr: &Expr) -> Vec<Name> {
                 .chain(fun_free)
                 .collect()
         }
+        Seq(s, _, body) => free_vars_simp(s)
+            .into_iter()
+            .chain(free_vars_expr(body))
+            .collect(),
         Simp(s) => free_vars_simp(s),
     }
 }
@@ -209,30 +311,36 @@ fn free_vars_simp(simp: &Simp) -> Vec<Name> {
     match simp {
         Match(s, arms) => {
             let free_s = free_vars_simp(s);
-            let free_arms = arms.into_iter().flat_map(|(pat, body)| {
+            let free_arms = arms.iter().flat_map(|(pat, guard, body)| {
                 let nbound = bound_names_pat(pat);
                 let free_body = free_vars_simp(body);
 
                 free_body
                     .into_iter()
+                    .chain(guard.iter().flat_map(free_vars_simp))
                     .filter(|name| !nbound.contains(name))
                     .collect::<Vec<_>>()
             });
 
             free_s.into_iter().chain(free_arms).collect()
         }
-        FnCall(lhs, rhs) => free_vars_simp(lhs)
+        FnCall(lhs, rhs, _) => free_vars_simp(lhs)
             .into_iter()
             .chain(rhs.iter().flat_map(free_vars_simp))
             .collect(),
         Block(expr) => free_vars_expr(expr),
         Ref(name) => vec![name.clone()],
-        Data(_, args) => args.into_iter().flat_map(free_vars_simp).collect(),
-        Int(_) | Bool(_) | Unit => vec![],
+        Data(_, args) | Tuple(args) => args.iter().flat_map(free_vars_simp).collect(),
+        Int(_) | Float(_) | Str(_) | Char(_) | Bool(_) | Unit => vec![],
+        Field(record, ..) => free_vars_simp(record),
+        Update(record, fields, ..) => free_vars_simp(record)
+            .into_iter()
+            .chain(fields.iter().flat_map(|(_, value)| free_vars_simp(value)))
+            .collect(),
     }
 }
 
-pub fn eval_prog(program: &Program) -> Value {
+pub fn eval_prog(program: &Program) -> Result<Value, RuntimeError> {
     let mut env = Env::new();
     for data_def in &program.data_defs {
         env.data_defs
@@ -242,7 +350,15 @@ pub fn eval_prog(program: &Program) -> Value {
     eval_expr(env, program.expr.as_ref().unwrap())
 }
 
-fn eval_expr(env: Env, expr: &Expr) -> Value {
+// Reported as the CPS evaluator does, which doesn't know where the match was
+fn match_failed() -> RuntimeError {
+    RuntimeError {
+        message: "pattern match failed".to_string(),
+        loc: None,
+    }
+}
+
+fn eval_expr(env: Env, expr: &Expr) -> Result<Value, RuntimeError> {
     use Expr::*;
     match expr {
         Bind(pat, rhs, body) => {
@@ -252,9 +368,10 @@ fn eval_expr(env: Env, expr: &Expr) -> Value {
                 .iter()
                 .fold(env, |nenv, name| nenv.bind_late(name.clone()));
 
-            let value = eval_simp(env.clone(), rhs);
-            let nenv = eval_pattern_match(env, pat, &value)
-                .unwrap_or_else(|| panic!("Pattern match failed: {:?} = {:?}", pat, value));
+            let value = eval_simp(env.clone(), rhs)?;
+            let Some(nenv) = eval_pattern_match(env, pat, &value) else {
+                return Err(match_failed());
+            };
 
             eval_expr(nenv, body)
         }
@@ -283,34 +400,46 @@ fn eval_expr(env: Env, expr: &Expr) -> Value {
 
             eval_expr(nenv, body)
         }
+        Seq(s, _, body) => {
+            eval_simp(env.clone(), s)?;
+            eval_expr(env, body)
+        }
         Simp(s) => eval_simp(env, s),
     }
 }
 
-fn eval_simp(env: Env, simp: &Simp) -> Value {
+fn eval_simp(env: Env, simp: &Simp) -> Result<Value, RuntimeError> {
     use Simp::*;
     match simp {
         Match(s, arms) => {
-            let value = eval_simp(env.clone(), s);
-            for (pat, body) in arms {
-                if let Some(nenv) = eval_pattern_match(env.clone(), pat, &value) {
+            let value = eval_simp(env.clone(), s)?;
+            for (pat, guard, body) in arms {
+                let Some(nenv) = eval_pattern_match(env.clone(), pat, &value) else {
+                    continue;
+                };
+                // a false guard moves on to the next arm
+                let taken = match guard {
+                    Some(guard) => matches!(eval_simp(nenv.clone(), guard)?, Value::Bool(true)),
+                    None => true,
+                };
+                if taken {
                     return eval_simp(nenv, body);
                 }
             }
-            panic!(
-                "Pattern match failed: {:?} does not match any of {:?}",
-                value, arms
-            );
+            Err(match_failed())
         }
-        FnCall(lhs, rhs) => {
-            let lhs = eval_simp(env.clone(), lhs);
+        FnCall(lhs, rhs, loc) => {
+            let lhs = eval_simp(env.clone(), lhs)?;
             let arg_vals = rhs
                 .iter()
                 .map(|arg| eval_simp(env.clone(), arg))
-                .collect::<Vec<_>>();
+                .collect::<Result<Vec<_>, _>>()?;
 
             if let Value::BuiltIn(f) = lhs {
-                return f(arg_vals);
+                return f(arg_vals).map_err(|message| RuntimeError {
+                    message: message.to_string(),
+                    loc: Some(*loc),
+                });
             }
 
             let (fenv, fun) = match lhs {
@@ -327,7 +456,7 @@ fn eval_simp(env: Env, simp: &Simp) -> Value {
             eval_simp(fenv, &fun.body)
         }
         Block(expr) => eval_expr(env, expr),
-        Ref(name) => env
+        Ref(name) => Ok(env
             .bindings
             .get(name)
             .unwrap_or_else(|| panic!("Unbound name: {}", name))
@@ -335,14 +464,41 @@ fn eval_simp(env: Env, simp: &Simp) -> Value {
             .borrow()
             .as_ref()
             .unwrap_or_else(|| panic!("Uninitialized late binding: {}", name))
-            .clone(),
-        Int(n) => Value::Int(*n),
-        Bool(b) => Value::Bool(*b),
-        Unit => Value::Unit,
-        Data(name, args) => Value::Data(
+            .clone()),
+        Int(n) => Ok(Value::Int(n.clone())),
+        Float(x) => Ok(Value::Float(*x)),
+        Str(s) => Ok(Value::Str(s.clone())),
+        Char(c) => Ok(Value::Char(*c)),
+        Bool(b) => Ok(Value::Bool(*b)),
+        Unit => Ok(Value::Unit),
+        Data(name, args) => Ok(Value::Data(
             name.clone(),
-            args.iter().map(|arg| eval_simp(env.clone(), arg)).collect(),
-        ),
+            args.iter()
+                .map(|arg| eval_simp(env.clone(), arg))
+                .collect::<Result<_, _>>()?,
+        )),
+        Tuple(args) => Ok(Value::Tuple(
+            args.iter()
+                .map(|arg| eval_simp(env.clone(), arg))
+                .collect::<Result<_, _>>()?,
+        )),
+        Field(record, _, index, _) => {
+            let Value::Data(_, mut vals) = eval_simp(env, record)? else {
+                panic!("{} is not a record", record);
+            };
+            Ok(vals.swap_remove(index.unwrap()))
+        }
+        // the new values are computed after the record
+        Update(record, fields, resolved, _) => {
+            let Value::Data(name, mut vals) = eval_simp(env.clone(), record)? else {
+                panic!("{} is not a record", record);
+            };
+            let (_, indices) = resolved.as_ref().unwrap();
+            for ((_, value), i) in fields.iter().zip(indices) {
+                vals[*i] = eval_simp(env.clone(), value)?;
+            }
+            Ok(Value::Data(name, vals))
+        }
     }
 }
 
@@ -353,6 +509,10 @@ fn eval_pattern_match(env: Env, pat: &Pattern, value: &Value) -> Option<Env> {
             Value::Int(m) if n == m => Some(env),
             _ => None,
         },
+        Pattern::Char(c) => match value {
+            Value::Char(d) if c == d => Some(env),
+            _ => None,
+        },
         Pattern::Bool(b) => match value {
             Value::Bool(c) if b == c => Some(env),
             _ => None,
@@ -366,5 +526,22 @@ fn eval_pattern_match(env: Env, pat: &Pattern, value: &Value) -> Option<Env> {
                 }),
             _ => None,
         },
+        Pattern::Tuple(pats) => match value {
+            Value::Tuple(vals) => pats
+                .iter()
+                .zip(vals)
+                .try_fold(env.clone(), |nenv, (pat, val)| {
+                    eval_pattern_match(nenv, pat, val)
+                }),
+            _ => None,
+        },
+        Pattern::Wildcard => Some(env),
+        Pattern::Or(alts) => alts
+            .iter()
+            .find_map(|alt| eval_pattern_match(env.clone(), alt, value)),
+        Pattern::As(name, _, pat) => {
+            let nenv = eval_pattern_match(env, pat, value)?;
+            Some(nenv.bind(name.clone(), value.clone()))
+        }
     }
 }
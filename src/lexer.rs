@@ -1,5 +1,7 @@
 use logos::Logos;
 
+use crate::integer::Integer;
+
 #[derive(Logos, Clone, Debug, PartialEq)]
 #[logos(skip r"[ \t\n\f]+")]
 pub enum Token {
@@ -8,6 +10,8 @@ pub enum Token {
     Match,
     #[token("data")]
     Data,
+    #[token("type")]
+    TypeDef,
     #[token("fn")]
     Fn,
     #[token("let")]
@@ -16,10 +20,32 @@ pub enum Token {
     If,
     #[token("else")]
     Else,
+    #[token("with")]
+    With,
+    #[token("infixl")]
+    Infixl,
+    #[token("infixr")]
+    Infixr,
 
     // Literals
-    #[regex("-?[0-9]+", |lex| lex.slice().parse::<i64>().unwrap())]
-    Int(i64),
+    // as long as needed; big literals become bignums. The parser reads minus
+    // signs, so these are never negative.
+    #[regex("[0-9]+", |lex| lex.slice().parse::<Integer>().unwrap())]
+    Int(Integer),
+
+    #[regex(r"[0-9]+\.[0-9]+([eE][-+]?[0-9]+)?", |lex| lex.slice().parse::<f64>().unwrap())]
+    #[regex(r"[0-9]+[eE][-+]?[0-9]+", |lex| lex.slice().parse::<f64>().unwrap())]
+    Float(f64),
+
+    #[regex(r#""([^"\\]|\\.)*""#, |lex| unescape(&lex.slice()[1..lex.slice().len() - 1]))]
+    Str(String),
+
+    #[regex(r"'([^'\\]|\\.|\\u\{[0-9a-fA-F]+\})'", |lex| {
+        let text = unescape(&lex.slice()[1..lex.slice().len() - 1])?;
+        let mut chars = text.chars();
+        chars.next().filter(|_| chars.next().is_none())
+    })]
+    Char(char),
 
     #[token("true", |_| true)]
     #[token("false", |_| false)]
@@ -35,10 +61,16 @@ pub enum Token {
 
     #[token("|")]
     Pipe,
+    #[token("@")]
+    At,
     #[token(":")]
     Colon,
     #[token(",")]
     Comma,
+    #[token(";")]
+    Semi,
+    #[token(".")]
+    Dot,
     #[token("(")]
     POpen,
     #[token(")")]
@@ -48,8 +80,12 @@ pub enum Token {
     #[token("}")]
     BClose,
 
-    // Identifiers
-    #[regex("([a-zA-Z_<>][a-zA-Z0-9_]*)|([=<>]{2,})|[+*/]|-", |lex| lex.slice().to_string())]
+    // Identifiers, and operators made of any run of operator characters
+    // but `-`, which is always a token of its own. A minus is never glued to
+    // the operator before it, so `x*-1` and `|-1` read as `x * -1` and `| -1`.
+    #[regex("[a-zA-Z_][a-zA-Z0-9_]*", |lex| lex.slice().to_string())]
+    #[regex(r"[+*/%<>=!&|^~?$#]+", |lex| lex.slice().to_string(), priority = 1)]
+    #[token("-", |lex| lex.slice().to_string())]
     Ident(String),
 
     // comments
@@ -59,3 +95,55 @@ pub enum Token {
 
     EOF,
 }
+
+// The text of a string or char literal, with its escapes (\n, \t, \r, \0, \\,
+// \", \' and \u{...}) replaced
+pub fn unescape(text: &str) -> Option<String> {
+    let mut out = String::new();
+    let mut chars = text.chars();
+
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            out.push(c);
+            continue;
+        }
+
+        let c = match chars.next()? {
+            'n' => '\n',
+            't' => '\t',
+            'r' => '\r',
+            '0' => '\0',
+            c @ ('\\' | '"' | '\'') => c,
+            'u' => {
+                let rest = chars.as_str().strip_prefix('{')?;
+                let (hex, rest) = rest.split_once('}')?;
+                chars = rest.chars();
+                char::from_u32(u32::from_str_radix(hex, 16).ok()?)?
+            }
+            _ => return None,
+        };
+        out.push(c);
+    }
+
+    Some(out)
+}
+
+// The inverse of `unescape`, quoted; other control chars are written as \u{...}
+pub fn escape(text: &str) -> String {
+    let mut out = String::from('"');
+    for c in text.chars() {
+        match c {
+            '\n' => out.push_str("\\n"),
+            '\t' => out.push_str("\\t"),
+            '\r' => out.push_str("\\r"),
+            '\\' | '"' => {
+                out.push('\\');
+                out.push(c);
+            }
+            c if c.is_control() => out.push_str(&format!("\\u{{{:x}}}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
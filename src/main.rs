@@ -8,23 +8,134 @@ mod display_impls;
 mod cps;
 mod ast_to_cps;
 mod passes;
+mod pass_manager;
+mod verify;
+mod cps_parser;
+mod cps_eval;
+mod integer;
 
 use ast_to_cps::AstToCps;
+use cps::{CpsExpr, LitHigh};
 use logos::Logos;
-use passes::Shrinking;
-use passes::TreePass;
+use pass_manager::PassManager;
+use verify::Verifier;
 
 use crate::lexer::*;
 use crate::parser::*;
 use crate::checker::*;
 
+const USAGE: &str = "usage: language [compile] [-O0|-O1|-O2] [--passes=p1,p2,..] [--dump-after=pass] [--stats] [--from-cps] <file>";
+
+fn fail(msg: &str) -> ! {
+    eprintln!("{}\n{}", msg, USAGE);
+    std::process::exit(1)
+}
+
+struct Options {
+    file_name: String,
+    opt_level: u8,
+    passes: Option<Vec<String>>,
+    dump_after: Vec<String>,
+    stats: bool,
+    // the input is textual CPS instead of source code
+    from_cps: bool,
+}
+
+fn parse_args() -> Options {
+    let mut file_name = None;
+    let mut opt_level = 2;
+    let mut passes = None;
+    let mut dump_after = vec![];
+    let mut stats = false;
+    let mut from_cps = false;
+
+    let mut args = std::env::args().skip(1).peekable();
+    // `compile` is the only subcommand, and the default one
+    args.next_if(|arg| arg == "compile");
+
+    for arg in args {
+        if let Some(level) = arg.strip_prefix("-O") {
+            opt_level = level
+                .parse()
+                .unwrap_or_else(|_| fail(&format!("bad optimization level: {}", arg)));
+        } else if let Some(list) = arg.strip_prefix("--passes=") {
+            let list = list.split(',').filter(|p| !p.is_empty());
+            passes = Some(list.map(String::from).collect());
+        } else if let Some(pass) = arg.strip_prefix("--dump-after=") {
+            dump_after.push(pass.to_string());
+        } else if arg == "--stats" {
+            stats = true;
+        } else if arg == "--from-cps" {
+            from_cps = true;
+        } else if arg.starts_with('-') {
+            fail(&format!("unknown option: {}", arg));
+        } else {
+            file_name = Some(arg);
+        }
+    }
+
+    Options {
+        file_name: file_name.unwrap_or_else(|| fail("no file name given")),
+        opt_level,
+        passes,
+        dump_after,
+        stats,
+        from_cps,
+    }
+}
+
 fn main() {
-    // file name from first arg
-    let file_name = std::env::args().nth(1).expect("no file name given");
+    let options = parse_args();
+    let file_name = options.file_name;
+
+    let mut manager = match &options.passes {
+        Some(passes) => {
+            let passes = passes.iter().map(String::as_str).collect::<Vec<_>>();
+            PassManager::new(&passes)
+        }
+        None => PassManager::opt_level(options.opt_level),
+    }
+    .unwrap_or_else(|err| fail(&err));
+
+    for pass in &options.dump_after {
+        manager.dump_after(pass).unwrap_or_else(|err| fail(&err));
+    }
 
     let prog = std::fs::read_to_string(file_name.clone()).unwrap();
 
-    let lexer = Token::lexer(prog.as_str());
+    let cps = if options.from_cps {
+        let cps = cps_parser::parse(&prog)
+            .unwrap_or_else(|err| fail(&format!("bad CPS in {}: {}", file_name, err)));
+        // passes rename by substitution, which is only safe with unique binders
+        if let Err(err) = Verifier::new().unique_binders(true).verify(&cps) {
+            fail(&format!("ill-formed CPS in {}: {}", file_name, err));
+        }
+        cps
+    } else {
+        compile_source(&prog)
+    };
+
+    let cps = manager.run(cps);
+
+    println!("----- Optimized CPS -----");
+    println!("{:#}", cps);
+
+    println!("----- CPS Evaluator -----");
+    match cps_eval::eval(&cps) {
+        Ok(output) => println!("{}", output),
+        Err(err) => println!("{}", err),
+    }
+
+    if options.stats {
+        println!("----- Pass Statistics -----");
+        print!("{}", manager.report());
+    }
+    // println!("{:#?}", cps.free());
+}
+
+// Front end: source text to verified, unoptimized CPS
+fn compile_source(prog: &str) -> CpsExpr<LitHigh> {
+    let lexer = Token::lexer(prog);
     // println!("----- Lexer -----");
     // for token in lexer.clone() {
     //     println!("{:?}", token);
@@ -32,6 +143,9 @@ fn main() {
 
     let mut parser = Parser::new(lexer);
     let program = parser.parse_program();
+    for warning in &parser.warnings {
+        eprintln!("{}", warning);
+    }
     // println!("{}", &program);
 
     println!("----- Type Inference -----");
@@ -41,27 +155,20 @@ fn main() {
 
     println!("----- Tree Interpreter -----");
 
-    let output = interp::eval_prog(&program);
-    println!("{}", output);
+    match interp::eval_prog(&program) {
+        Ok(output) => println!("{}", output),
+        Err(err) => println!("{}", err),
+    }
 
     println!("----- CPS Lowering -----");
-    let mut cps = AstToCps::convert(program);
+    let cps = AstToCps::convert(program);
     // println!("{:#}", cps);
 
-    println!("----- Optimized CPS -----");
-
-    let mut len = cps.len();
-    let mut prev_len = len + 1;
-
-    while len < prev_len {
-        prev_len = len;
-
-        let mut pass = Shrinking::new();
-        cps = pass.apply(cps);
-
-        len = cps.len();
+    if cfg!(debug_assertions) {
+        if let Err(err) = Verifier::new().unique_binders(true).verify(&cps) {
+            panic!("ill-formed CPS after lowering: {}", err);
+        }
     }
 
-    println!("{:#}", cps);
-    // println!("{:#?}", cps.free());
+    cps
 }
@@ -1,27 +1,64 @@
-use logos::Lexer;
+use logos::{Lexer, Logos, SpannedIter};
 
 use crate::ast::*;
 use crate::lexer::*;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::iter::Peekable;
 
 pub struct Parser<'a> {
-    pub lexer: Peekable<Lexer<'a, Token>>,
+    pub lexer: Peekable<SpannedIter<'a, Token>>,
+    source: &'a str,
+    // offsets at which the lines of the source start
+    line_starts: Vec<usize>,
     // map from type constructor to data type name
     ty_cons: HashMap<Name, DataDef>,
+    // declared operators, with the function each one calls
+    operators: HashMap<Name, (Fixity, Name)>,
+    // column of the arms of the innermost match not closed by braces, once
+    // one of them has started a line
+    arm_col: Option<usize>,
+    // every identifier in the source, which made-up names must avoid
+    taken: HashSet<String>,
+    pub warnings: Vec<String>,
 }
 
 impl<'a> Parser<'a> {
     pub fn new(lexer: Lexer<'a, Token>) -> Self {
+        let source = lexer.source();
+        let newlines = source.match_indices('\n').map(|(i, _)| i + 1);
+
         Parser {
-            lexer: lexer.peekable(),
+            source,
+            line_starts: std::iter::once(0).chain(newlines).collect(),
+            lexer: lexer.spanned().peekable(),
             ty_cons: HashMap::new(),
+            operators: HashMap::new(),
+            arm_col: None,
+            taken: Token::lexer(source)
+                .filter_map(|token| match token {
+                    Ok(Token::Ident(name)) => Some(name),
+                    _ => None,
+                })
+                .collect(),
+            warnings: vec![],
+        }
+    }
+
+    // A name no source variable has, for binders the parser introduces
+    fn fresh(&mut self, hint: &str) -> Name {
+        let mut i = 0;
+        loop {
+            let name = format!("{}_{}", hint, i);
+            i += 1;
+            if self.taken.insert(name.clone()) {
+                return Name(name);
+            }
         }
     }
 
     fn end(&mut self) -> bool {
         let peek = self.lexer.peek();
-        let peek = peek.map(|x| x.is_ok());
+        let peek = peek.map(|(x, _)| x.is_ok());
 
         match peek {
             Some(true) => false,
@@ -33,17 +70,56 @@ impl<'a> Parser<'a> {
         if self.end() {
             &Token::EOF
         } else {
-            self.lexer.peek().unwrap().as_ref().unwrap()
+            self.lexer.peek().unwrap().0.as_ref().unwrap()
         }
     }
 
     fn accept(&mut self) -> Token {
         let token = self.lexer.next();
-        let token = token.unwrap().unwrap();
+        let token = token.unwrap().0.unwrap();
 
         token
     }
 
+    // where the next token starts
+    fn loc(&mut self) -> Loc {
+        let offset = match self.lexer.peek() {
+            Some((_, span)) => span.start,
+            None => self.source.len(),
+        };
+
+        let line = self.line_starts.partition_point(|&start| start <= offset);
+        Loc {
+            line,
+            col: offset - self.line_starts[line - 1] + 1,
+        }
+    }
+
+    // the column of the next token, if it is the first on its line
+    fn line_start(&mut self) -> Option<usize> {
+        let offset = match self.lexer.peek() {
+            Some((_, span)) => span.start,
+            None => self.source.len(),
+        };
+
+        let line = self.line_starts.partition_point(|&start| start <= offset);
+        let start = self.line_starts[line - 1];
+        self.source[start..offset]
+            .trim()
+            .is_empty()
+            .then_some(offset - start + 1)
+    }
+
+    // the token after the next one
+    fn peek_second(&mut self) -> Token {
+        let mut lexer = self.lexer.clone();
+        lexer.next();
+        match lexer.next() {
+            Some((Ok(token), _)) => token,
+            _ => Token::EOF,
+        }
+    }
+
     fn expect(&mut self, token: Token) {
         let next = self.accept();
 
@@ -64,14 +140,15 @@ impl<'a> Parser<'a> {
     pub fn parse_program(&mut self) -> Program {
         let mut program = Program {
             data_defs: Vec::new(),
-            // type_defs: Vec::new(),
+            type_defs: Vec::new(),
             expr: None,
         };
 
         while !self.end() {
             match self.peek() {
                 Token::Data => program.data_defs.push(self.parse_data_ref()),
-                // Token::TypeDef => program.type_defs.push(self.parse_type_def()),
+                Token::TypeDef => program.type_defs.push(self.parse_type_def()),
+                Token::Infixl | Token::Infixr => self.parse_fixity_decl(),
                 _ => break,
             }
         }
@@ -100,10 +177,26 @@ impl<'a> Parser<'a> {
         match self.peek() {
             Token::Let => self.parse_let(),
             Token::Fn => self.parse_fndef(),
-            _ => Expr::Simp(self.parse_simp()),
+            _ => {
+                let loc = self.loc();
+                let simp = self.parse_simp();
+                if self.peek() == &Token::Semi {
+                    self.accept();
+                    // a `;` closing a block or the program discards its value
+                    let rest = match self.peek() {
+                        Token::BClose | Token::EOF => Expr::Simp(Simp::Unit),
+                        _ => self.parse_expr(),
+                    };
+                    Expr::Seq(simp, loc, Box::new(rest))
+                } else {
+                    Expr::Simp(simp)
+                }
+            }
         }
     }
 
+    // `let pat = e else { .. }` is a match, where the else branch is taken
+    // when pat doesn't match
     fn parse_let(&mut self) -> Expr {
         self.expect(Token::Let);
         let pattern = self.parse_pattern();
@@ -111,35 +204,109 @@ impl<'a> Parser<'a> {
         self.expect(Token::Eq);
         let rhs = self.parse_simp();
 
+        let otherwise = match self.peek() {
+            Token::Else => {
+                self.accept();
+                Some(self.parse_simp())
+            }
+            _ => None,
+        };
+
         let body = self.parse_expr();
 
-        Expr::Bind(pattern, rhs, Box::new(body))
+        match otherwise {
+            Some(otherwise) => {
+                let arms = vec![
+                    (pattern, None, Simp::Block(Box::new(body))),
+                    (Pattern::Wildcard, None, otherwise),
+                ];
+                Expr::Simp(Simp::Match(Box::new(rhs), arms))
+            }
+            None => Expr::Bind(pattern, rhs, Box::new(body)),
+        }
     }
 
+    // alternatives separated by `|`
     fn parse_pattern(&mut self) -> Pattern {
+        let mut alts = vec![self.parse_as_pattern()];
+        while self.peek() == &Token::Pipe {
+            self.accept();
+            alts.push(self.parse_as_pattern());
+        }
+
+        if alts.len() == 1 {
+            alts.pop().unwrap()
+        } else {
+            Pattern::Or(alts)
+        }
+    }
+
+    fn parse_as_pattern(&mut self) -> Pattern {
         match self.peek() {
+            Token::Ident(name) if name == "_" => {
+                self.accept();
+                Pattern::Wildcard
+            }
+            Token::Ident(name) if name == "-" => {
+                self.accept();
+                match self.accept() {
+                    Token::Int(n) => Pattern::Int(n.neg()),
+                    token => panic!("Expected a number after '-', got {:?}", token),
+                }
+            }
             Token::Ident(_) => {
                 let name = self.expect_name();
 
                 if self.ty_cons.contains_key(&name) {
-                    let pats = self.parse_pat_list();
                     let df = self.ty_cons.get(&name).unwrap().clone();
+                    let pats = if self.peek() == &Token::BOpen {
+                        // fields left out match anything
+                        let fields = self.parse_fields(|s, name| match s.peek() {
+                            Token::Colon => {
+                                s.accept();
+                                s.parse_pattern()
+                            }
+                            _ => Pattern::Var(name, fresh_tv()),
+                        });
+                        self.record_args(&df, &name, fields, |_| Some(Pattern::Wildcard))
+                    } else {
+                        self.parse_pat_list()
+                    };
                     Pattern::Data(df, name, pats)
                 } else {
                     let ty = self.parse_otype();
-                    Pattern::Var(name, ty)
+                    if self.peek() == &Token::At {
+                        self.accept();
+                        Pattern::As(name, ty, Box::new(self.parse_as_pattern()))
+                    } else {
+                        Pattern::Var(name, ty)
+                    }
                 }
             }
             Token::Int(n) => {
-                let n = *n;
+                let n = n.clone();
                 self.accept();
                 Pattern::Int(n)
             }
+            Token::Char(c) => {
+                let c = *c;
+                self.accept();
+                Pattern::Char(c)
+            }
             Token::Bool(b) => {
                 let b = *b;
                 self.accept();
                 Pattern::Bool(b)
             }
+            // a parenthesized pattern, or a tuple of two or more
+            Token::POpen => {
+                let mut pats = self.parse_pat_list();
+                if pats.len() == 1 {
+                    pats.pop().unwrap()
+                } else {
+                    Pattern::Tuple(pats)
+                }
+            }
             _ => panic!("Expected pattern, got {:?}", self.peek()),
         }
     }
@@ -149,6 +316,10 @@ impl<'a> Parser<'a> {
         match self.peek() {
             Token::POpen => {
                 self.accept();
+                if self.peek() == &Token::PClose {
+                    self.accept();
+                    return pats;
+                }
                 pats.push(self.parse_pattern());
 
                 loop {
@@ -176,55 +347,138 @@ impl<'a> Parser<'a> {
             Token::If => self.parse_if(),
             Token::Match => self.parse_match(),
             Token::BOpen => {
+                let loc = self.loc();
                 self.accept();
                 let expr = self.parse_expr();
 
-                self.expect(Token::BClose);
+                match (expr, self.peek()) {
+                    // functional update of a record, { r with field = value, .. }
+                    (Expr::Simp(record), Token::With) => {
+                        self.accept();
+                        let mut fields: Vec<(Name, Simp)> = vec![];
+                        loop {
+                            let name = self.expect_name();
+                            if fields.iter().any(|(field, _)| *field == name) {
+                                panic!("Field {} is updated twice, at {}", name, loc);
+                            }
+                            self.expect(Token::Eq);
+                            fields.push((name, self.parse_simp()));
+
+                            match self.accept() {
+                                Token::Comma => {}
+                                Token::BClose => break,
+                                token => panic!("Expected ',' or '}}', got {:?}", token),
+                            }
+                        }
+
+                        Simp::Update(Box::new(record), fields, None, loc)
+                    }
+                    (expr, _) => {
+                        self.expect(Token::BClose);
 
-                Simp::Block(Box::new(expr))
+                        Simp::Block(Box::new(expr))
+                    }
+                }
             }
             _ => self.parse_simple_ops(0),
         }
     }
 
+    // Consecutive definitions of the same name are clauses of one function,
+    // tried in order. Parameters that aren't all plain names become a match
+    // on the arguments.
     fn parse_fndef(&mut self) -> Expr {
-        self.accept(); // consume 'fn'
-        let name = self.expect_name();
-        self.expect(Token::POpen);
+        let (name, pats, ret, body) = self.parse_clause();
+        let mut clauses = vec![(pats, body)];
 
-        // parse name of args, comma separated
-        let mut args = Vec::new();
-        if self.peek() != &Token::PClose {
-            args.push((self.expect_name(), self.parse_otype()));
-            loop {
-                match self.peek() {
-                    Token::Comma => {
-                        self.accept();
-                        args.push((self.expect_name(), self.parse_otype()));
-                    }
-                    Token::PClose => {
-                        self.accept();
-                        break;
-                    }
-                    _ => panic!("Expected ',' or ')', got {:?}", self.peek()),
-                }
+        while self.peek() == &Token::Fn && self.peek_second() == Token::Ident(name.0.clone()) {
+            let (_, pats, clause_ret, body) = self.parse_clause();
+            if pats.len() != clauses[0].0.len() {
+                panic!("Clauses of {} have different numbers of parameters", name);
             }
+            if clause_ret.is_some() {
+                panic!("Only the first clause of {} can give the return type", name);
+            }
+            clauses.push((pats, body));
         }
 
-        let ret = self.parse_otype();
-        self.expect(Token::Eq);
+        let vars = clauses[0]
+            .0
+            .iter()
+            .map(|pat| match pat {
+                Pattern::Var(name, ty) => Some((name.clone(), ty.clone())),
+                _ => None,
+            })
+            .collect::<Option<Vec<_>>>();
+
+        let (args, body) = match vars {
+            Some(args) if clauses.len() == 1 => (args, clauses.pop().unwrap().1),
+            _ => {
+                let arity = clauses[0].0.len();
+                if arity == 0 {
+                    panic!("{} has several clauses and no parameters", name);
+                }
+
+                let args = (0..arity)
+                    .map(|_| (self.fresh("arg"), fresh_tv()))
+                    .collect::<Vec<_>>();
+                let mut refs = args
+                    .iter()
+                    .map(|(arg, _)| Simp::Ref(arg.clone()))
+                    .collect::<Vec<_>>();
+                let scrutinee = match arity {
+                    1 => refs.pop().unwrap(),
+                    _ => Simp::Tuple(refs),
+                };
+
+                let arms = clauses
+                    .into_iter()
+                    .map(|(mut pats, body)| {
+                        let pat = match arity {
+                            1 => pats.pop().unwrap(),
+                            _ => Pattern::Tuple(pats),
+                        };
+                        (pat, None, body)
+                    })
+                    .collect();
+                (args, Simp::Match(Box::new(scrutinee), arms))
+            }
+        };
 
         Expr::FnDef(
             FnDef {
                 name,
                 args,
-                body: Box::new(self.parse_simp()),
-                ret,
+                body: Box::new(body),
+                ret: ret.unwrap_or_else(fresh_tv),
             },
             Box::new(self.parse_expr()),
         )
     }
 
+    // `fn name(pat, ..): T = body`, with the return type if one is given
+    fn parse_clause(&mut self) -> (Name, Vec<Pattern>, Option<Type>, Simp) {
+        self.expect(Token::Fn);
+        let name = self.expect_name();
+        let pats = self.parse_pat_list();
+
+        let ret = match self.peek() {
+            Token::Colon => Some(self.parse_otype()),
+            _ => None,
+        };
+        self.expect(Token::Eq);
+
+        (name, pats, ret, self.parse_simp())
+    }
+
+    // the fixity of an infix operator, and the function it stands for
+    fn operator(&self, name: &Name) -> Option<(Fixity, Name)> {
+        match self.operators.get(name) {
+            Some(op) => Some(op.clone()),
+            None => name.fixity().map(|fixity| (fixity, name.clone())),
+        }
+    }
+
     fn parse_simple_ops(&mut self, min_prec: i32) -> Simp {
         let mut lhs = self.parse_utight();
         loop {
@@ -233,42 +487,82 @@ impl<'a> Parser<'a> {
                 _ => break,
             };
 
-            if !name.valid() || name.prec() < min_prec {
+            let loc = self.loc();
+            let (fixity, fun) = match self.operator(&name) {
+                Some(op) => op,
+                None if name.symbolic() && !name.unary() => {
+                    panic!("Unknown operator {}, at {}", name, loc)
+                }
+                None => break,
+            };
+            if fixity.prec < min_prec {
                 break;
             }
 
             self.accept();
-            let new_min = name.prec() + name.assoc();
+            let new_min = match fixity.assoc {
+                Assoc::Left => fixity.prec + 1,
+                Assoc::Right => fixity.prec,
+            };
             let rhs = self.parse_simple_ops(new_min);
 
-            let fname = Box::new(Simp::Ref(name));
+            let fname = Box::new(Simp::Ref(fun));
 
-            lhs = Simp::FnCall(fname, vec![lhs, rhs]);
+            lhs = Simp::FnCall(fname, vec![lhs, rhs], loc);
         }
 
         lhs
     }
 
     fn parse_tight(&mut self) -> Simp {
+        let loc = self.loc();
         let lhs = self.parse_atom();
 
-        match self.peek() {
+        let mut lhs = match self.peek() {
             Token::POpen => {
                 let args = self.parse_simp_list();
-                Simp::FnCall(Box::new(lhs), args)
+                Simp::FnCall(Box::new(lhs), args, loc)
             }
             _ => lhs,
+        };
+
+        while self.peek() == &Token::Dot {
+            let loc = self.loc();
+            self.accept();
+            lhs = Simp::Field(Box::new(lhs), self.expect_name(), None, loc);
         }
+
+        lhs
     }
 
     fn parse_utight(&mut self) -> Simp {
         match self.peek() {
+            // a minus sign before a literal is part of it
+            Token::Ident(name) if name == "-" => {
+                let loc = self.loc();
+                self.accept();
+                match self.peek().clone() {
+                    Token::Int(n) => {
+                        self.accept();
+                        Simp::Int(n.neg())
+                    }
+                    Token::Float(x) => {
+                        self.accept();
+                        Simp::Float(-x)
+                    }
+                    _ => {
+                        let rest = self.parse_tight();
+                        Simp::FnCall(Box::new(Simp::Ref(Name::new("-"))), vec![rest], loc)
+                    }
+                }
+            }
             Token::Ident(name) if Name(name.clone()).unary() => {
+                let loc = self.loc();
                 let name = self.expect_name();
                 let name = Simp::Ref(name);
 
                 let rest = self.parse_tight();
-                Simp::FnCall(Box::new(name), vec![rest])
+                Simp::FnCall(Box::new(name), vec![rest], loc)
             }
             _ => self.parse_tight(),
         }
@@ -285,16 +579,41 @@ impl<'a> Parser<'a> {
                     self.accept();
                     Simp::Unit
                 } else {
-                    let simp = self.parse_simp();
+                    // a parenthesized expression, or a tuple of two or more
+                    let mut simps = vec![self.parse_simp()];
+                    while self.peek() == &Token::Comma {
+                        self.accept();
+                        simps.push(self.parse_simp());
+                    }
 
                     self.expect(Token::PClose);
 
-                    simp
+                    if simps.len() == 1 {
+                        simps.pop().unwrap()
+                    } else {
+                        Simp::Tuple(simps)
+                    }
                 }
             }
             Token::Ident(name) if self.ty_cons.contains_key(&Name(name.clone())) => {
                 let name = self.expect_name();
-                let vals = self.parse_simp_list();
+                let df = self.ty_cons.get(&name).unwrap().clone();
+
+                let vals = if self.peek() == &Token::BOpen && !df.cons[&name].fields.is_empty() {
+                    // `name` alone is short for `name: name`
+                    let fields = self.parse_fields(|s, name| match s.peek() {
+                        Token::Colon => {
+                            s.accept();
+                            s.parse_simp()
+                        }
+                        _ => Simp::Ref(name),
+                    });
+                    self.record_args(&df, &name, fields, |field| {
+                        panic!("Field {} of {} is missing", field, name)
+                    })
+                } else {
+                    self.parse_simp_list()
+                };
 
                 Simp::Data(name, vals)
             }
@@ -306,6 +625,18 @@ impl<'a> Parser<'a> {
                 self.accept();
                 Simp::Int(n)
             }
+            Token::Float(x) => {
+                self.accept();
+                Simp::Float(x)
+            }
+            Token::Str(s) => {
+                self.accept();
+                Simp::Str(s)
+            }
+            Token::Char(c) => {
+                self.accept();
+                Simp::Char(c)
+            }
             Token::Bool(b) => {
                 self.accept();
                 Simp::Bool(b)
@@ -319,6 +650,10 @@ impl<'a> Parser<'a> {
         match self.peek() {
             Token::POpen => {
                 self.accept();
+                if self.peek() == &Token::PClose {
+                    self.accept();
+                    return slist;
+                }
                 slist.push(self.parse_simp());
 
                 loop {
@@ -357,27 +692,68 @@ impl<'a> Parser<'a> {
 
         Simp::Match(
             Box::new(cond),
-            vec![(Pattern::Bool(true), then), (Pattern::Bool(false), els)],
+            vec![(Pattern::Bool(true), None, then), (Pattern::Bool(false), None, els)],
         )
     }
 
+    // Arms in braces end at the closing brace. Without them, the arms go on
+    // until one starts a line no further right than the arms of the enclosing
+    // match, so a nested match is closed by going back to the outer arms.
     fn parse_match(&mut self) -> Simp {
+        let loc = self.loc();
         self.expect(Token::Match);
         let expr = self.parse_simp();
 
+        let braced = self.peek() == &Token::BOpen;
+        if braced {
+            self.accept();
+        }
+        let enclosing = self.arm_col;
+        let outer = if braced { None } else { enclosing };
+        let mut own = None;
+
         let mut cases = Vec::new();
         loop {
             match self.peek() {
                 Token::Pipe => {
+                    if let Some(col) = self.line_start() {
+                        let left = outer.is_some_and(|outer| col <= outer);
+                        if left && !cases.is_empty() && own != Some(col) {
+                            break;
+                        }
+                        if left && cases.is_empty() {
+                            self.warnings.push(format!(
+                                "Warning: the arms of the match at {} are indented like those \
+                                 of the enclosing match, and all belong to the inner one; \
+                                 put them in braces to close it",
+                                loc
+                            ));
+                        }
+                        own = own.or(Some(col));
+                    }
+                    self.arm_col = if braced { None } else { own };
+
                     self.accept();
                     let pattern = self.parse_pattern();
+                    let guard = match self.peek() {
+                        Token::If => {
+                            self.accept();
+                            Some(self.parse_simp())
+                        }
+                        _ => None,
+                    };
                     self.expect(Token::FatArrow);
                     let simp = self.parse_simp();
-                    cases.push((pattern, simp));
+                    cases.push((pattern, guard, simp));
                 }
                 _ => break,
             }
         }
+        self.arm_col = enclosing;
+
+        if braced {
+            self.expect(Token::BClose);
+        }
 
         if cases.len() == 0 {
             panic!("Match statement at {:?} has no cases", self.peek());
@@ -393,6 +769,16 @@ impl<'a> Parser<'a> {
 
         let mut cons = HashMap::new();
 
+        // a record on its own has a constructor named after the type
+        if self.peek() == &Token::BOpen {
+            let fields = self.parse_fields(|s, _| {
+                s.expect(Token::Colon);
+                s.parse_type()
+            });
+            cons.insert(name.clone(), Self::record_cons(fields));
+            return DataDef { name, cons };
+        }
+
         let con = self.parse_cons();
         cons.insert(con.0, con.1);
 
@@ -414,23 +800,115 @@ impl<'a> Parser<'a> {
         let tag = self.expect_name();
         let args = match self.peek() {
             Token::POpen => self.parse_type_list(),
+            Token::BOpen => {
+                let fields = self.parse_fields(|s, _| {
+                    s.expect(Token::Colon);
+                    s.parse_type()
+                });
+                return (tag, Self::record_cons(fields));
+            }
             _ => Vec::new(),
         };
 
-        (tag, Cons { args })
+        (tag, Cons { args, fields: vec![] })
     }
 
-    // fn parse_type_def(&mut self) -> TypeDef {
-    //     self.expect(Token::TypeDef);
-    //     let name = self.expect_name();
-    //     self.expect(Token::Keyword("="));
-    //     let ty = self.parse_type();
+    fn record_cons(fields: Vec<(Name, Type)>) -> Cons {
+        let (fields, args) = fields.into_iter().unzip();
+        Cons { args, fields }
+    }
 
-    //     TypeDef {
-    //         name,
-    //         ty,
-    //     }
-    // }
+    // `{ name ..., name ... }`, where field parses what follows each name
+    fn parse_fields<T>(&mut self, field: impl Fn(&mut Self, Name) -> T) -> Vec<(Name, T)> {
+        self.expect(Token::BOpen);
+
+        let mut fields: Vec<(Name, T)> = vec![];
+        while self.peek() != &Token::BClose {
+            let name = self.expect_name();
+            if fields.iter().any(|(other, _)| *other == name) {
+                panic!("Field {} is given twice", name);
+            }
+            let value = field(self, name.clone());
+            fields.push((name, value));
+
+            match self.peek() {
+                Token::Comma => {
+                    self.accept();
+                }
+                Token::BClose => {}
+                _ => panic!("Expected ',' or '}}', got {:?}", self.peek()),
+            }
+        }
+        self.expect(Token::BClose);
+
+        fields
+    }
+
+    // The named fields of constructor cons in declaration order, with missing
+    // giving the ones left out
+    fn record_args<T>(
+        &self,
+        df: &DataDef,
+        cons: &Name,
+        mut fields: Vec<(Name, T)>,
+        missing: impl Fn(&Name) -> Option<T>,
+    ) -> Vec<T> {
+        let declared = &df.cons[cons].fields;
+        if let Some((unknown, _)) = fields.iter().find(|(name, _)| !declared.contains(name)) {
+            panic!("{} has no field {}", cons, unknown);
+        }
+
+        declared
+            .iter()
+            .map(|name| match fields.iter().position(|(field, _)| field == name) {
+                Some(i) => fields.remove(i).1,
+                None => missing(name).unwrap(),
+            })
+            .collect()
+    }
+
+    fn parse_type_def(&mut self) -> TypeDef {
+        self.expect(Token::TypeDef);
+        let name = self.expect_name();
+        self.expect(Token::Eq);
+        let ty = self.parse_type();
+
+        TypeDef {
+            name,
+            ty,
+        }
+    }
+
+    // `infixl 6 ++ = append` makes `a ++ b` a call of append(a, b)
+    fn parse_fixity_decl(&mut self) {
+        let assoc = match self.accept() {
+            Token::Infixl => Assoc::Left,
+            _ => Assoc::Right,
+        };
+        let prec = match self.accept() {
+            Token::Int(n) => n.to_i64().filter(|n| (0..=9).contains(n)),
+            _ => None,
+        };
+        let Some(prec) = prec else {
+            panic!("Expected a precedence from 0 to 9, at {}", self.loc());
+        };
+
+        let name = self.expect_name();
+        if !name.symbolic() || name.unary() || name.fixity().is_some() {
+            panic!("{} can't be declared as an operator", name);
+        }
+        if self.operators.contains_key(&name) {
+            panic!("Operator {} is declared twice", name);
+        }
+        self.expect(Token::Eq);
+        let fun = self.expect_name();
+
+        let fixity = Fixity {
+            prec: prec as i32,
+            assoc,
+        };
+        self.operators.insert(name, (fixity, fun));
+    }
 
     fn parse_type(&mut self) -> Type {
         let mut lhs = self.parse_type_list();
@@ -441,7 +919,7 @@ impl<'a> Parser<'a> {
             Type::Fn(lhs, Box::new(rhs))
         } else {
             if lhs.len() > 1 {
-                unimplemented!("Tuples")
+                Type::Tuple(lhs)
             } else {
                 let ty = lhs.pop().unwrap();
                 ty
@@ -475,6 +953,9 @@ impl<'a> Parser<'a> {
             let name = self.expect_name();
             let ty = match name.0.as_str() {
                 "Int" => Type::Int,
+                "Float" => Type::Float,
+                "String" => Type::Str,
+                "Char" => Type::Char,
                 "Unit" => Type::Unit,
                 "Bool" => Type::Bool,
                 _ => Type::UserDef(name),
@@ -484,3 +965,92 @@ impl<'a> Parser<'a> {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn line_starts() {
+        let mut parser = Parser::new(Token::lexer("a b\n  c\nd"));
+        let mut starts = vec![];
+        while !parser.end() {
+            starts.push(parser.line_start());
+            parser.accept();
+        }
+        assert_eq!(starts, [Some(1), None, Some(3), Some(1)]);
+    }
+
+    #[test]
+    fn locations() {
+        let mut parser = Parser::new(Token::lexer("a\n  bc d\n\n\tee"));
+        let mut locs = vec![];
+        while !parser.end() {
+            let loc = parser.loc();
+            locs.push((loc.line, loc.col));
+            parser.accept();
+        }
+        assert_eq!(locs, [(1, 1), (2, 3), (2, 6), (4, 2)]);
+
+        let loc = parser.loc();
+        assert_eq!((loc.line, loc.col), (4, 4));
+    }
+
+    // the bodies of the arms of the match the source is, and the warnings
+    // parsing it gave
+    fn arms(source: &str) -> (Vec<Simp>, Vec<String>) {
+        let mut parser = Parser::new(Token::lexer(source));
+        let program = parser.parse_program();
+        let Some(Expr::Simp(Simp::Match(_, arms))) = program.expr else {
+            panic!("expected a match");
+        };
+        let bodies = arms.into_iter().map(|(_, _, body)| body).collect();
+        (bodies, parser.warnings)
+    }
+
+    fn inner_arms(body: &Simp) -> usize {
+        match body {
+            Simp::Match(_, arms) => arms.len(),
+            _ => panic!("expected a nested match"),
+        }
+    }
+
+    // an arm back at the outer column closes the nested match
+    #[test]
+    fn nested_match_closed_by_indentation() {
+        let (arms, warnings) = arms(
+            "
+            match a
+                | 0 => match b
+                    | 0 => 1
+                    | _ => 2
+                | _ => 3
+            ",
+        );
+        assert_eq!(arms.len(), 2);
+        assert_eq!(inner_arms(&arms[0]), 2);
+        assert!(warnings.is_empty());
+    }
+
+    // arms in the outer column can't close a match they start
+    #[test]
+    fn nested_match_in_outer_column() {
+        let (arms, warnings) = arms(
+            "
+            match a
+                | 0 => match b
+                | 0 => 1
+                | _ => 2
+            ",
+        );
+        assert_eq!(arms.len(), 1);
+        assert_eq!(inner_arms(&arms[0]), 2);
+        assert_eq!(
+            warnings,
+            [
+                "Warning: the arms of the match at 3:24 are indented like those of the \
+              enclosing match, and all belong to the inner one; put them in braces to close it"
+            ]
+        );
+    }
+}
@@ -0,0 +1,220 @@
+use std::collections::HashSet;
+
+use crate::{
+    cps::{CpsExpr as BaseCpsExpr, LitHigh},
+    passes::{Contification, Cse, Inlining, Shrinking, Simplify, Stats, TreePass},
+    verify::Verifier,
+};
+
+type CpsExpr = BaseCpsExpr<LitHigh>;
+type PassFn = fn(CpsExpr, &mut Stats) -> CpsExpr;
+
+// Largest function body `inline` copies into a call site
+const INLINE_BUDGET: usize = 16;
+
+pub const PASSES: [&str; 5] = ["shrink", "simplify", "cse", "inline", "contify"];
+
+fn lookup(name: &str) -> Option<PassFn> {
+    match name {
+        "shrink" => Some(|tree, stats| Shrinking::new().apply(tree, stats)),
+        "simplify" => Some(|tree, stats| Simplify::new().apply(tree, stats)),
+        "cse" => Some(|tree, stats| Cse::new().apply(tree, stats)),
+        "inline" => Some(|tree, stats| Inlining::new(INLINE_BUDGET).apply(tree, stats)),
+        "contify" => Some(|tree, stats| Contification::new().apply(tree, stats)),
+        _ => None,
+    }
+}
+
+// Totals for one pass over every time it ran
+#[derive(Debug, Clone, Default)]
+pub struct PassStats {
+    pub runs: usize,
+    pub removed: isize,
+    pub stats: Stats,
+}
+
+pub struct PassManager {
+    pipeline: Vec<(&'static str, PassFn)>,
+    max_rounds: usize,
+    dump_after: HashSet<String>,
+    // rounds over all runs, for the report
+    rounds: usize,
+    stats: Vec<PassStats>,
+}
+
+impl PassManager {
+    pub fn new(pipeline: &[&str]) -> Result<Self, String> {
+        let pipeline = pipeline
+            .iter()
+            .map(|name| {
+                let pass = lookup(name).ok_or_else(|| format!("unknown pass: {}", name))?;
+                let name = PASSES.iter().find(|known| *known == name).unwrap();
+                Ok((*name, pass))
+            })
+            .collect::<Result<Vec<_>, String>>()?;
+
+        Ok(Self {
+            stats: vec![PassStats::default(); pipeline.len()],
+            pipeline,
+            max_rounds: 64,
+            dump_after: HashSet::new(),
+            rounds: 0,
+        })
+    }
+
+    pub fn opt_level(level: u8) -> Result<Self, String> {
+        match level {
+            0 => Self::new(&[]),
+            1 => Self::new(&["shrink"]),
+            2 => Self::new(&PASSES),
+            _ => Err(format!("unknown optimization level: -O{}", level)),
+        }
+    }
+
+    pub fn dump_after(&mut self, name: &str) -> Result<(), String> {
+        if !PASSES.contains(&name) {
+            return Err(format!("unknown pass: {}", name));
+        }
+
+        self.dump_after.insert(name.to_string());
+        Ok(())
+    }
+
+    // Runs the pipeline over and over until a whole round leaves the tree unchanged
+    // up to renaming
+    pub fn run(&mut self, mut tree: CpsExpr) -> CpsExpr {
+        if self.pipeline.is_empty() {
+            return tree;
+        }
+
+        let mut round = 0;
+        while round < self.max_rounds {
+            round += 1;
+            self.rounds += 1;
+            let prev = tree.clone();
+
+            for (i, (name, pass)) in self.pipeline.iter().enumerate() {
+                let before = tree.len() as isize;
+                let mut stats = Stats::default();
+                tree = pass(tree, &mut stats);
+
+                let total = &mut self.stats[i];
+                total.runs += 1;
+                total.removed += before - tree.len() as isize;
+                total.stats.add(&stats);
+
+                if cfg!(debug_assertions) {
+                    if let Err(err) = Verifier::new().unique_binders(true).verify(&tree) {
+                        panic!("ill-formed CPS after {}: {}", name, err);
+                    }
+                }
+
+                if self.dump_after.contains(*name) {
+                    println!("----- After {} (round {}) -----", name, round);
+                    println!("{:#}", tree);
+                }
+            }
+
+            // passes may rename binders without changing anything else
+            if tree.alpha_eq(&prev) {
+                break;
+            }
+        }
+
+        tree
+    }
+
+    pub fn report(&self) -> String {
+        let mut out = format!("{} round(s)\n", self.rounds);
+        for ((name, _), total) in self.pipeline.iter().zip(&self.stats) {
+            out.push_str(&format!(
+                "{:<8} runs: {:<3} nodes removed: {:<5} folded: {:<4} shared: {:<4} inlined: {:<4} contified: {}\n",
+                name,
+                total.runs,
+                total.removed,
+                total.stats.folded,
+                total.stats.shared,
+                total.stats.inlined,
+                total.stats.contified,
+            ));
+        }
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ast::Name;
+
+    // const a = 1; const b = 2; let s = a + b; halt(s)
+    fn sum() -> CpsExpr {
+        BaseCpsExpr::Const {
+            name: Name::new("a"),
+            value: LitHigh::Int(1.into()),
+            body: Box::new(BaseCpsExpr::Const {
+                name: Name::new("b"),
+                value: LitHigh::Int(2.into()),
+                body: Box::new(BaseCpsExpr::Prim {
+                    name: Name::new("s"),
+                    op: Name::new("+"),
+                    args: vec![Name::new("a"), Name::new("b")],
+                    loc: None,
+                    body: Box::new(BaseCpsExpr::Halt(Name::new("s"))),
+                }),
+            }),
+        }
+    }
+
+    // the round that folds changes the tree, the next one doesn't and ends the run
+    #[test]
+    fn fixed_point() {
+        let mut manager = PassManager::opt_level(1).unwrap();
+        let tree = manager.run(sum());
+
+        let expected = BaseCpsExpr::Const {
+            name: Name::new("s"),
+            value: LitHigh::Int(3.into()),
+            body: Box::new(BaseCpsExpr::Halt(Name::new("s"))),
+        };
+        assert_eq!(tree, expected);
+        assert!(manager.report().starts_with("2 round(s)\n"));
+    }
+
+    #[test]
+    fn opt_levels() {
+        assert_eq!(PassManager::opt_level(0).unwrap().run(sum()), sum());
+        assert_eq!(
+            PassManager::opt_level(2).unwrap().pipeline.len(),
+            PASSES.len()
+        );
+        assert_eq!(
+            PassManager::opt_level(3).err().unwrap(),
+            "unknown optimization level: -O3"
+        );
+    }
+
+    #[test]
+    fn unknown_pass() {
+        let err = PassManager::new(&["shrink", "fold"]).err().unwrap();
+        assert_eq!(err, "unknown pass: fold");
+
+        let mut manager = PassManager::new(&["shrink"]).unwrap();
+        assert_eq!(
+            manager.dump_after("fold"),
+            Err("unknown pass: fold".to_string())
+        );
+        assert_eq!(manager.dump_after("inline"), Ok(()));
+    }
+
+    // each run gets the whole budget of rounds
+    #[test]
+    fn rounds_per_run() {
+        let mut manager = PassManager::opt_level(1).unwrap();
+        manager.max_rounds = 1;
+        let first = manager.run(sum());
+        assert_eq!(manager.run(sum()), first);
+        assert_ne!(first, sum());
+        assert!(manager.report().starts_with("2 round(s)\n"));
+    }
+}
@@ -1,19 +1,44 @@
-use std::{collections::HashMap, ops::Rem};
+use std::collections::{HashMap, HashSet};
 
 use crate::{
-    ast::Name,
-    cps::{CntDef, CpsExpr as BaseCpsExpr, FunDef, LitHigh, Subst, Substitutable},
+    ast::{Name, Op},
+    builtins::{
+        checked_arith, checked_char_at, checked_string_to_int, checked_substring, str_compare,
+        str_length,
+    },
+    cps::{traps, CntDef, CpsExpr as BaseCpsExpr, FunDef, LitHigh, NameSupply, Subst, Substitutable},
+    integer::Integer,
 };
 type CpsExpr = BaseCpsExpr<LitHigh>;
 
 pub trait TreePass {
-    fn apply(self, tree: CpsExpr) -> CpsExpr;
+    fn apply(self, tree: CpsExpr, stats: &mut Stats) -> CpsExpr;
+}
+
+// What a pass did, beyond the change in tree size
+#[derive(Debug, Clone, Default)]
+pub struct Stats {
+    pub folded: usize,
+    pub inlined: usize,
+    pub contified: usize,
+    pub shared: usize,
+}
+
+impl Stats {
+    pub fn add(&mut self, other: &Stats) {
+        self.folded += other.folded;
+        self.inlined += other.inlined;
+        self.contified += other.contified;
+        self.shared += other.shared;
+    }
 }
 
 #[derive(Clone)]
 pub struct Shrinking {
     consts: HashMap<Name, LitHigh>,
     consts_inv: HashMap<LitHigh, Name>,
+    // blocks allocated in scope, as their descriptor followed by their fields
+    data: HashMap<Name, Vec<Name>>,
 }
 
 impl Shrinking {
@@ -21,46 +46,105 @@ impl Shrinking {
         Self {
             consts: HashMap::new(),
             consts_inv: HashMap::new(),
+            data: HashMap::new(),
+        }
+    }
+
+    // the value of op applied to constants, unless it would trap at runtime
+    // or has an effect (like `print`)
+    fn fold(&self, op: &Name, args: &[Name]) -> Option<LitHigh> {
+        if op == &Name::new("data") || !(pure(op) || traps(op)) {
+            return None;
+        }
+
+        let args = args
+            .iter()
+            .map(|arg| self.consts.get(arg).cloned())
+            .collect::<Option<Vec<_>>>()?;
+        eval_op(op, &args).ok()
+    }
+
+    // what a `desc` or `field` of a known block reads
+    fn known_field(&self, op: &Name, args: &[Name]) -> Option<Name> {
+        let block = self.data.get(args.first()?)?;
+
+        match op.0.as_str() {
+            "desc" => block.first().cloned(),
+            "field" => match self.consts.get(args.get(1)?)? {
+                LitHigh::Int(i) => block.get(usize::try_from(i.to_i64()?).ok()? + 1).cloned(),
+                LitHigh::Float(_) | LitHigh::Str(_) => None,
+            },
+            _ => None,
         }
     }
 }
 
+// Primitives that can be dropped when their result is unused
+fn pure(op: &Name) -> bool {
+    let known = op.valid()
+        || matches!(
+            op.0.as_str(),
+            "id" | "data" | "desc" | "field" | "to_float" | "concat" | "length" | "compare" | "int_to_string"
+        );
+    known && !traps(op)
+}
+
 impl TreePass for Shrinking {
-    fn apply(mut self, tree: CpsExpr) -> CpsExpr {
+    fn apply(mut self, tree: CpsExpr, stats: &mut Stats) -> CpsExpr {
         use BaseCpsExpr::*;
 
         match tree {
             Const { name, value, body } => {
                 if let Some(existing) = self.consts_inv.get(&value) {
                     let nbody = body.subst(Subst::one(name.clone(), existing.clone()));
-                    self.apply(nbody)
+                    self.apply(nbody, stats)
                 } else {
                     self.consts.insert(name.clone(), value.clone());
                     self.consts_inv.insert(value.clone(), name.clone());
 
-                    Const {
-                        name,
-                        value,
-                        body: Box::new(self.apply(*body)),
+                    let body = self.apply(*body, stats);
+                    if body.free().contains(&name) {
+                        Const {
+                            name,
+                            value,
+                            body: Box::new(body),
+                        }
+                    } else {
+                        body
                     }
                 }
             }
 
             #[rustfmt::skip]
-            Prim { name, op, args, body } => {
-                let const_args = args.iter().all(|arg| self.consts.contains_key(arg)) && op != Name::new("data");
-                if const_args {
-                    let args = args.into_iter().map(|arg| self.consts.remove(&arg).unwrap()).collect();
-                    let value = eval_op(op, args);
-                    Const { name, value, body: Box::new(self.apply(*body)) }
-                }
-                // else if op == Name::new("id") {
-                //     let actual = args[0].clone();
-                //     let nbody = body.subst(Subst::one(name.clone(), actual));
-                //     self.apply(nbody)
-                // }
+            Prim { name, op, args, loc, body } => {
+                if let Some(value) = self.fold(&op, &args) {
+                    stats.folded += 1;
+                    self.apply(Const { name, value, body }, stats)
+                }
+                else if let Some(known) = self.known_field(&op, &args) {
+                    stats.folded += 1;
+                    let nbody = body.subst(Subst::one(name, known));
+                    self.apply(nbody, stats)
+                }
+                else if op == Name::new("id") {
+                    stats.folded += 1;
+                    let nbody = body.subst(Subst::one(name, args[0].clone()));
+                    self.apply(nbody, stats)
+                }
+                else if pure(&op) {
+                    if op == Name::new("data") {
+                        self.data.insert(name.clone(), args.clone());
+                    }
+
+                    let body = self.apply(*body, stats);
+                    if body.free().contains(&name) {
+                        Prim { name, op, args, loc, body: Box::new(body) }
+                    } else {
+                        body
+                    }
+                }
                 else {
-                    Prim { name, op, args, body: Box::new(self.apply(*body)) }
+                    Prim { name, op, args, loc, body: Box::new(self.apply(*body, stats)) }
                 }
             }
 
@@ -69,9 +153,9 @@ impl TreePass for Shrinking {
                 Cnts {
                     cnts: cnts.into_iter().map(|cnt| {
                         let CntDef { name, args, body } = cnt;
-                        CntDef { name, args, body: self.clone().apply(body) }
+                        CntDef { name, args, body: self.clone().apply(body, stats) }
                     }).collect(),
-                    body: Box::new(self.apply(*body)),
+                    body: Box::new(self.apply(*body, stats)),
                 },
 
             #[rustfmt::skip]
@@ -79,22 +163,19 @@ impl TreePass for Shrinking {
                 Funs {
                     funs: funs.into_iter().map(|fun| {
                             let FunDef { name, args, body, ret} = fun;
-                            FunDef { name, args, body: self.clone().apply(body), ret }
+                            FunDef { name, args, body: self.clone().apply(body, stats), ret }
                         }).collect(),
-                    body: Box::new(self.apply(*body)),
+                    body: Box::new(self.apply(*body, stats)),
                 },
 
             AppC { cnt, args } => AppC { cnt, args },
             AppF { fun, ret, args } => AppF { fun, ret, args },
 
             If { op, args, t, f } => {
-                let const_args = args.iter().all(|arg| self.consts.contains_key(arg));
-                if const_args {
-                    let args = args.into_iter().map(|arg| self.consts.remove(&arg).unwrap()).collect();
-                    let value = eval_op(op, args);
+                if let Some(value) = self.fold(&op, &args) {
+                    stats.folded += 1;
 
-                    let LitHigh::Int(value) = value;
-                    if value > 0 {
+                    if matches!(value, LitHigh::Int(n) if n.is_positive()) {
                         AppC { cnt: t, args: vec![] }
                     } else {
                         AppC { cnt: f, args: vec![] }
@@ -108,27 +189,1722 @@ impl TreePass for Shrinking {
     }
 }
 
-fn eval_op(op: Name, args: Vec<LitHigh>) -> LitHigh {
-    let op = op.0;
-    let args = args.into_iter().map(|LitHigh::Int(i)| i).collect::<Vec<_>>();
-
-    let out = match op.as_str() {
-        "+" => args[0] + args[1],
-        "-" => args[0] - args[1],
-        "*" => args[0] * args[1],
-        "/" => args[0] / args[1],
-        "~" => !args[0],
-        "==" => (args[0] == args[1]) as i64,
-        "!=" => (args[0] != args[1]) as i64,
-        "<" => (args[0] < args[1]) as i64,
-        ">" => (args[0] > args[1]) as i64,
-        "<=" => (args[0] <= args[1]) as i64,
-        ">=" => (args[0] >= args[1]) as i64,
-        "&&" => (args[0] & args[1]),
-        "||" => (args[0] | args[1]),
-        "!" => !(args[0] > 0) as i64,
-        _ => panic!("unknown op: {}", op),
+// Rewrites primitives and tests whose operands are only partly known, using
+// algebraic identities, and turns multiplication and division by powers of two
+// into shifts. Booleans are 0 and 1, which `!(!b) = b` relies on.
+pub struct Simplify;
+
+impl Simplify {
+    pub fn new() -> Self {
+        Simplify
+    }
+}
+
+impl TreePass for Simplify {
+    fn apply(self, tree: CpsExpr, stats: &mut Stats) -> CpsExpr {
+        let mut simplifier = Simplifier {
+            supply: NameSupply::new(&tree),
+            rewritten: 0,
+        };
+
+        let tree = simplifier.expr(tree, Facts::default());
+        stats.folded += simplifier.rewritten;
+        tree
+    }
+}
+
+// What is known about the names in scope: constant values, and the primitive
+// each name was computed by
+#[derive(Clone, Default)]
+struct Facts {
+    consts: HashMap<Name, i64>,
+    defs: HashMap<Name, (Name, Vec<Name>)>,
+}
+
+impl Facts {
+    fn constant(&self, name: &Name) -> Option<i64> {
+        self.consts.get(name).copied()
+    }
+
+    // the test a boolean name was computed by
+    fn test(&self, name: &Name) -> Option<&(Name, Vec<Name>)> {
+        self.defs.get(name).filter(|(op, _)| {
+            matches!(
+                op.0.as_str(),
+                "==" | "!=" | "<" | ">" | "<=" | ">=" | "!" | "==." | "!=." | "<." | ">." | "<=." | ">=."
+            )
+        })
+    }
+}
+
+enum Rewrite {
+    Alias(Name),
+    Const(i64),
+    // shift by a constant amount
+    Shift(&'static str, Name, i64),
+}
+
+fn power_of_two(n: i64) -> Option<i64> {
+    (n > 1 && n & (n - 1) == 0).then(|| n.trailing_zeros() as i64)
+}
+
+struct Simplifier {
+    supply: NameSupply,
+    rewritten: usize,
+}
+
+impl Simplifier {
+    fn prim(&self, facts: &Facts, op: &Name, args: &[Name]) -> Option<Rewrite> {
+        use Rewrite::*;
+        let c = |name: &Name| facts.constant(name);
+
+        let rewrite = match (op.0.as_str(), args) {
+            ("+", [x, y]) if c(y) == Some(0) => Alias(x.clone()),
+            ("+", [x, y]) if c(x) == Some(0) => Alias(y.clone()),
+            ("-", [x, y]) if c(y) == Some(0) => Alias(x.clone()),
+            ("-", [x, y]) if x == y => Const(0),
+            ("*", [x, y]) if c(x) == Some(0) || c(y) == Some(0) => Const(0),
+            ("*", [x, y]) if c(y) == Some(1) => Alias(x.clone()),
+            ("*", [x, y]) if c(x) == Some(1) => Alias(y.clone()),
+            ("*", [x, y]) => match (c(x).and_then(power_of_two), c(y).and_then(power_of_two)) {
+                (_, Some(k)) => Shift("shl", x.clone(), k),
+                (Some(k), _) => Shift("shl", y.clone(), k),
+                _ => return None,
+            },
+            ("/", [x, y]) if c(y) == Some(1) => Alias(x.clone()),
+            ("/", [x, y]) => Shift("shr", x.clone(), c(y).and_then(power_of_two)?),
+            ("==" | "<=" | ">=", [x, y]) if x == y => Const(1),
+            ("!=" | "<" | ">", [x, y]) if x == y => Const(0),
+            ("&&", [x, y]) if c(x) == Some(0) || c(y) == Some(0) => Const(0),
+            ("&&", [x, y]) if c(y) == Some(1) => Alias(x.clone()),
+            ("&&", [x, y]) if c(x) == Some(1) => Alias(y.clone()),
+            ("||", [x, y]) if c(x) == Some(1) || c(y) == Some(1) => Const(1),
+            ("||", [x, y]) if c(y) == Some(0) => Alias(x.clone()),
+            ("||", [x, y]) if c(x) == Some(0) => Alias(y.clone()),
+            // both are involutions
+            ("!" | "~", [x]) => match facts.defs.get(x)? {
+                (inner, args) if inner == op => Alias(args[0].clone()),
+                _ => return None,
+            },
+            _ => return None,
+        };
+
+        Some(rewrite)
+    }
+
+    // An equivalent test that skips materializing a boolean, and whether
+    // the branches trade places under it
+    fn test(&self, facts: &Facts, op: &Name, args: &[Name]) -> Option<(Name, Vec<Name>, bool)> {
+        let (k, b) = match (op.0.as_str(), args) {
+            ("==" | "!=", [x, y]) if matches!(facts.constant(x), Some(0 | 1)) => {
+                (facts.constant(x)?, y)
+            }
+            ("==" | "!=", [x, y]) if matches!(facts.constant(y), Some(0 | 1)) => {
+                (facts.constant(y)?, x)
+            }
+            _ => return None,
+        };
+
+        let (op2, args2) = facts.test(b)?;
+        let swap = (k == 0) != (op.0 == "!=");
+        Some((op2.clone(), args2.clone(), swap))
+    }
+
+    fn expr(&mut self, tree: CpsExpr, mut facts: Facts) -> CpsExpr {
+        use BaseCpsExpr::*;
+
+        match tree {
+            Const { name, value, body } => {
+                // only small constants enable rewrites
+                if let LitHigh::Int(n) = &value {
+                    if let Some(n) = n.to_i64() {
+                        facts.consts.insert(name.clone(), n);
+                    }
+                }
+                Const {
+                    name,
+                    value,
+                    body: Box::new(self.expr(*body, facts)),
+                }
+            }
+            Prim {
+                name,
+                op,
+                args,
+                loc,
+                body,
+            } => match self.prim(&facts, &op, &args) {
+                Some(Rewrite::Alias(to)) => {
+                    self.rewritten += 1;
+                    self.expr(body.subst(Subst::one(name, to)), facts)
+                }
+                Some(Rewrite::Const(n)) => {
+                    self.rewritten += 1;
+                    let value = LitHigh::Int(n.into());
+                    self.expr(Const { name, value, body }, facts)
+                }
+                Some(Rewrite::Shift(shift, x, k)) => {
+                    self.rewritten += 1;
+                    let amount = self.supply.fresh(&Name(format!("c{}", k)));
+                    let prim = Prim {
+                        name,
+                        op: Name::new(shift),
+                        args: vec![x, amount.clone()],
+                        loc,
+                        body,
+                    };
+                    self.expr(
+                        Const {
+                            name: amount,
+                            value: LitHigh::Int(k.into()),
+                            body: Box::new(prim),
+                        },
+                        facts,
+                    )
+                }
+                None => {
+                    facts.defs.insert(name.clone(), (op.clone(), args.clone()));
+                    Prim {
+                        name,
+                        op,
+                        args,
+                        loc,
+                        body: Box::new(self.expr(*body, facts)),
+                    }
+                }
+            },
+            Cnts { cnts, body } => Cnts {
+                cnts: cnts
+                    .into_iter()
+                    .map(|cnt| CntDef {
+                        body: self.expr(cnt.body, facts.clone()),
+                        ..cnt
+                    })
+                    .collect(),
+                body: Box::new(self.expr(*body, facts)),
+            },
+            // keep function bodies from capturing more than they did
+            Funs { funs, body } => Funs {
+                funs: funs
+                    .into_iter()
+                    .map(|fun| FunDef {
+                        body: self.expr(fun.body, Facts::default()),
+                        ..fun
+                    })
+                    .collect(),
+                body: Box::new(self.expr(*body, facts)),
+            },
+            If {
+                mut op,
+                mut args,
+                mut t,
+                mut f,
+            } => {
+                while let Some((nop, nargs, swap)) = self.test(&facts, &op, &args) {
+                    self.rewritten += 1;
+                    (op, args) = (nop, nargs);
+                    if swap {
+                        std::mem::swap(&mut t, &mut f);
+                    }
+                }
+
+                // `!b` as a test is `b` with the branches swapped
+                while op == Name::new("!") {
+                    let Some((nop, nargs)) = facts.test(&args[0]).cloned() else {
+                        break;
+                    };
+                    self.rewritten += 1;
+                    (op, args) = (nop, nargs);
+                    std::mem::swap(&mut t, &mut f);
+                }
+
+                let same = args.len() == 2 && args[0] == args[1];
+                match op.0.as_str() {
+                    "==" | "<=" | ">=" if same => {
+                        self.rewritten += 1;
+                        AppC { cnt: t, args: vec![] }
+                    }
+                    "!=" | "<" | ">" if same => {
+                        self.rewritten += 1;
+                        AppC { cnt: f, args: vec![] }
+                    }
+                    _ => If { op, args, t, f },
+                }
+            }
+            AppC { .. } | AppF { .. } | Halt(_) => tree,
+        }
+    }
+}
+
+// Reuses the result of a pure primitive already computed by a dominating
+// binding. Continuation bodies see everything computed before their group;
+// function bodies start afresh so that no closure grows to share a value.
+#[derive(Clone)]
+pub struct Cse {
+    available: HashMap<(Name, Vec<Name>), Name>,
+}
+
+impl Cse {
+    pub fn new() -> Self {
+        Self {
+            available: HashMap::new(),
+        }
+    }
+
+    fn key(op: &Name, args: &[Name]) -> (Name, Vec<Name>) {
+        let mut args = args.to_vec();
+        if matches!(op.0.as_str(), "+" | "*" | "==" | "!=" | "&&" | "||") {
+            args.sort_by(|a, b| a.0.cmp(&b.0));
+        }
+        (op.clone(), args)
+    }
+}
+
+impl TreePass for Cse {
+    fn apply(mut self, tree: CpsExpr, stats: &mut Stats) -> CpsExpr {
+        use BaseCpsExpr::*;
+
+        match tree {
+            Const { name, value, body } => Const {
+                name,
+                value,
+                body: Box::new(self.apply(*body, stats)),
+            },
+            // allocations stay distinct; a repeated trapping op can't trap
+            // if the first one didn't
+            Prim {
+                name,
+                op,
+                args,
+                loc,
+                body,
+            } if (pure(&op) || traps(&op)) && op != Name::new("data") => {
+                let key = Self::key(&op, &args);
+                if let Some(existing) = self.available.get(&key) {
+                    stats.shared += 1;
+                    let nbody = body.subst(Subst::one(name, existing.clone()));
+                    self.apply(nbody, stats)
+                } else {
+                    self.available.insert(key, name.clone());
+                    Prim {
+                        name,
+                        op,
+                        args,
+                        loc,
+                        body: Box::new(self.apply(*body, stats)),
+                    }
+                }
+            }
+            Prim {
+                name,
+                op,
+                args,
+                loc,
+                body,
+            } => Prim {
+                name,
+                op,
+                args,
+                loc,
+                body: Box::new(self.apply(*body, stats)),
+            },
+            Cnts { cnts, body } => Cnts {
+                cnts: cnts
+                    .into_iter()
+                    .map(|cnt| CntDef {
+                        body: self.clone().apply(cnt.body, stats),
+                        ..cnt
+                    })
+                    .collect(),
+                body: Box::new(self.apply(*body, stats)),
+            },
+            Funs { funs, body } => Funs {
+                funs: funs
+                    .into_iter()
+                    .map(|fun| FunDef {
+                        body: Cse::new().apply(fun.body, stats),
+                        ..fun
+                    })
+                    .collect(),
+                body: Box::new(self.apply(*body, stats)),
+            },
+            AppC { .. } | AppF { .. } | If { .. } | Halt(_) => tree,
+        }
+    }
+}
+
+// How often each name is referenced, and how often as the target of an AppC
+#[derive(Default)]
+struct Census {
+    uses: HashMap<Name, usize>,
+    jumps: HashMap<Name, usize>,
+    // names referenced from inside some function body
+    escaping: HashSet<Name>,
+}
+
+impl Census {
+    fn of(tree: &CpsExpr) -> Self {
+        let mut census = Self::default();
+        census.visit(tree);
+        census
+    }
+
+    fn uses(&self, name: &Name) -> usize {
+        self.uses.get(name).copied().unwrap_or(0)
+    }
+
+    fn jumps(&self, name: &Name) -> usize {
+        self.jumps.get(name).copied().unwrap_or(0)
+    }
+
+    fn mention(&mut self, name: &Name) {
+        *self.uses.entry(name.clone()).or_insert(0) += 1;
+    }
+
+    fn visit(&mut self, tree: &CpsExpr) {
+        use BaseCpsExpr::*;
+
+        match tree {
+            Const { body, .. } => self.visit(body),
+            Prim { args, body, .. } => {
+                args.iter().for_each(|arg| self.mention(arg));
+                self.visit(body)
+            }
+            Cnts { cnts, body } => {
+                cnts.iter().for_each(|cnt| self.visit(&cnt.body));
+                self.visit(body)
+            }
+            Funs { funs, body } => {
+                for fun in funs {
+                    self.escaping.extend(fun.free());
+                    self.visit(&fun.body);
+                }
+                self.visit(body)
+            }
+            AppC { cnt, args } => {
+                *self.jumps.entry(cnt.clone()).or_insert(0) += 1;
+                self.mention(cnt);
+                args.iter().for_each(|arg| self.mention(arg));
+            }
+            AppF { fun, ret, args } => {
+                self.mention(fun);
+                self.mention(ret);
+                args.iter().for_each(|arg| self.mention(arg));
+            }
+            If { args, t, f, .. } => {
+                args.iter().for_each(|arg| self.mention(arg));
+                self.mention(t);
+                self.mention(f);
+            }
+            Halt(name) => self.mention(name),
+        }
+    }
+
+    // a renamed copy of a body references its local names just as often
+    fn rename(&mut self, from: &Name, to: &Name) {
+        if let Some(n) = self.uses.get(from).copied() {
+            self.uses.insert(to.clone(), n);
+        }
+        if let Some(n) = self.jumps.get(from).copied() {
+            self.jumps.insert(to.clone(), n);
+        }
+        if self.escaping.contains(from) {
+            self.escaping.insert(to.clone());
+        }
+    }
+}
+
+// Maps each name in scope to the binding site that introduced it
+type Scope = HashMap<Name, usize>;
+
+// Binding sites of the free names of a definition, as seen where it is defined
+type Snapshot = Vec<(Name, Option<usize>)>;
+
+fn snapshot(scope: &Scope, free: HashSet<Name>) -> Snapshot {
+    free.into_iter()
+        .map(|name| {
+            let site = scope.get(&name).copied();
+            (name, site)
+        })
+        .collect()
+}
+
+fn visible(scope: &Scope, snap: &Snapshot) -> bool {
+    snap.iter()
+        .all(|(name, site)| scope.get(name).copied() == *site)
+}
+
+/// Beta-contracts continuations that are jumped to exactly once, and inlines
+/// non-recursive functions that are either called once or no larger than
+/// `budget` nodes. Continuations that only forward their arguments to another
+/// continuation are eta-reduced, and definitions that are never referenced
+/// are dropped.
+pub struct Inlining {
+    budget: usize,
+}
+
+impl Inlining {
+    pub fn new(budget: usize) -> Self {
+        Self { budget }
+    }
+}
+
+impl TreePass for Inlining {
+    fn apply(self, tree: CpsExpr, stats: &mut Stats) -> CpsExpr {
+        let mut inliner = Inliner {
+            budget: self.budget,
+            census: Census::of(&tree),
+            supply: NameSupply::new(&tree),
+            sites: 0,
+            cnts: HashMap::new(),
+            funs: HashMap::new(),
+            aliases: HashMap::new(),
+            needed: HashSet::new(),
+            inlined: 0,
+        };
+
+        let tree = inliner.expr(tree, Scope::new());
+        stats.inlined += inliner.inlined;
+        tree
+    }
+}
+
+struct Inliner {
+    budget: usize,
+    census: Census,
+    supply: NameSupply,
+    sites: usize,
+
+    // candidates, keyed by binding site
+    cnts: HashMap<usize, (CntDef<LitHigh>, Snapshot)>,
+    funs: HashMap<usize, (FunDef<LitHigh>, Snapshot)>,
+
+    // eta-reducible continuations, and those that are still referenced
+    // somewhere their target is not visible
+    aliases: HashMap<usize, (Name, Option<usize>)>,
+    needed: HashSet<usize>,
+
+    inlined: usize,
+}
+
+enum Pending {
+    Done(CntDef<LitHigh>),
+    Moved(usize),
+    Alias(usize, CntDef<LitHigh>),
+}
+
+// The continuation `cnt` jumps to if all it does is forward its arguments
+fn eta_target(cnt: &CntDef<LitHigh>) -> Option<&Name> {
+    match &cnt.body {
+        BaseCpsExpr::AppC { cnt: target, args } if args == &cnt.args && target != &cnt.name => {
+            Some(target)
+        }
+        _ => None,
+    }
+}
+
+impl Inliner {
+    fn bind(&mut self, scope: &mut Scope, name: &Name) -> usize {
+        self.sites += 1;
+        scope.insert(name.clone(), self.sites);
+        self.sites
+    }
+
+    fn resolve(&mut self, scope: &Scope, name: Name) -> Name {
+        let Some(site) = scope.get(&name).copied() else {
+            return name;
+        };
+
+        match self.aliases.get(&site).cloned() {
+            Some((target, tsite)) if scope.get(&target).copied() == tsite => {
+                self.resolve(scope, target)
+            }
+            Some(_) => {
+                self.needed.insert(site);
+                name
+            }
+            None => name,
+        }
+    }
+
+    // Copies `body` with every binder renamed apart and the formals replaced
+    // by the actuals. Gives up if a binder shadows a name free in the body.
+    fn instantiate(
+        &mut self,
+        body: &CpsExpr,
+        formals: &[Name],
+        actuals: &[Name],
+    ) -> Option<CpsExpr> {
+        if formals.len() != actuals.len() {
+            return None;
+        }
+
+        let binders = body.binders();
+        let mut free = body.free();
+        for formal in formals {
+            free.remove(formal);
+        }
+
+        if binders
+            .iter()
+            .any(|b| free.contains(b) || formals.contains(b))
+        {
+            return None;
+        }
+
+        let mut fresh = Subst::new();
+        for name in binders.iter().chain(formals) {
+            if !fresh.map.contains_key(name) {
+                let new = self.supply.fresh(name);
+                self.census.rename(name, &new);
+                fresh.insert(name.clone(), new);
+            }
+        }
+
+        let mut actual = Subst::new();
+        for (formal, arg) in formals.iter().zip(actuals) {
+            actual.insert(fresh.apply(formal), arg.clone());
+        }
+
+        Some(body.subst(fresh).subst(actual))
+    }
+
+    fn cnt(&mut self, cnt: CntDef<LitHigh>, scope: &Scope) -> CntDef<LitHigh> {
+        let mut cscope = scope.clone();
+        for arg in &cnt.args {
+            self.bind(&mut cscope, arg);
+        }
+
+        let CntDef { name, args, body } = cnt;
+        let body = self.expr(body, cscope);
+        CntDef { name, args, body }
+    }
+
+    fn expr(&mut self, tree: CpsExpr, mut scope: Scope) -> CpsExpr {
+        use BaseCpsExpr::*;
+
+        match tree {
+            Const { name, value, body } => {
+                self.bind(&mut scope, &name);
+                Const {
+                    name,
+                    value,
+                    body: Box::new(self.expr(*body, scope)),
+                }
+            }
+
+            #[rustfmt::skip]
+            Prim { name, op, args, loc, body } => {
+                self.bind(&mut scope, &name);
+                Prim { name, op, args, loc, body: Box::new(self.expr(*body, scope)) }
+            }
+
+            Cnts { cnts, body } => {
+                let cnts = cnts
+                    .into_iter()
+                    .filter(|cnt| self.census.uses(&cnt.name) > 0)
+                    .collect::<Vec<_>>();
+
+                let sites = cnts
+                    .iter()
+                    .map(|cnt| self.bind(&mut scope, &cnt.name))
+                    .collect::<Vec<_>>();
+
+                let group = cnts
+                    .iter()
+                    .map(|cnt| cnt.name.clone())
+                    .collect::<HashSet<_>>();
+
+                // single-jump continuations are only expanded where they are
+                // used, which may be in the body of a sibling, so they are all
+                // registered before any body is visited
+                let mut pending = vec![];
+                for (cnt, site) in cnts.into_iter().zip(sites) {
+                    let alias = eta_target(&cnt)
+                        .filter(|target| !group.contains(*target))
+                        .map(|target| (target.clone(), scope.get(target).copied()))
+                        .filter(|(_, tsite)| tsite.is_none_or(|t| !self.cnts.contains_key(&t)));
+
+                    if let Some(alias) = alias {
+                        self.aliases.insert(site, alias);
+                        pending.push(Pending::Alias(site, cnt));
+                        continue;
+                    }
+
+                    let moved = self.census.uses(&cnt.name) == 1
+                        && self.census.jumps(&cnt.name) == 1
+                        && !self.census.escaping.contains(&cnt.name);
+
+                    if moved {
+                        let snap = snapshot(&scope, cnt.free());
+                        self.cnts.insert(site, (cnt, snap));
+                        pending.push(Pending::Moved(site));
+                    } else {
+                        pending.push(Pending::Done(cnt));
+                    }
+                }
+
+                let pending = pending
+                    .into_iter()
+                    .map(|cnt| match cnt {
+                        Pending::Done(cnt) => Pending::Done(self.cnt(cnt, &scope)),
+                        cnt => cnt,
+                    })
+                    .collect::<Vec<_>>();
+
+                let body = self.expr(*body, scope.clone());
+
+                // the ones that were not expanded anywhere stay, and their
+                // bodies are visited like any other
+                let pending = pending
+                    .into_iter()
+                    .filter_map(|cnt| match cnt {
+                        Pending::Moved(site) => {
+                            let (cnt, _) = self.cnts.remove(&site)?;
+                            Some(Pending::Done(self.cnt(cnt, &scope)))
+                        }
+                        cnt => Some(cnt),
+                    })
+                    .collect::<Vec<_>>();
+
+                let cnts = pending
+                    .into_iter()
+                    .filter_map(|cnt| match cnt {
+                        Pending::Done(cnt) => Some(cnt),
+                        Pending::Moved(_) => unreachable!(),
+                        Pending::Alias(site, cnt) => {
+                            self.aliases.remove(&site);
+                            self.needed.remove(&site).then_some(cnt)
+                        }
+                    })
+                    .collect::<Vec<_>>();
+
+                if cnts.is_empty() {
+                    body
+                } else {
+                    Cnts {
+                        cnts,
+                        body: Box::new(body),
+                    }
+                }
+            }
+
+            Funs { funs, body } => {
+                let funs = funs
+                    .into_iter()
+                    .filter(|fun| self.census.uses(&fun.name) > 0)
+                    .collect::<Vec<_>>();
+
+                let sites = funs
+                    .iter()
+                    .map(|fun| self.bind(&mut scope, &fun.name))
+                    .collect::<Vec<_>>();
+
+                let group = funs
+                    .iter()
+                    .map(|fun| fun.name.clone())
+                    .collect::<HashSet<_>>();
+
+                let funs = funs
+                    .into_iter()
+                    .map(|fun| {
+                        let mut fscope = scope.clone();
+                        self.bind(&mut fscope, &fun.ret);
+                        for arg in &fun.args {
+                            self.bind(&mut fscope, arg);
+                        }
+
+                        let FunDef {
+                            name,
+                            ret,
+                            args,
+                            body,
+                        } = fun;
+                        let body = self.expr(body, fscope);
+                        FunDef {
+                            name,
+                            ret,
+                            args,
+                            body,
+                        }
+                    })
+                    .collect::<Vec<_>>();
+
+                for (fun, site) in funs.iter().zip(sites.iter()) {
+                    let recursive = !fun.body.free().is_disjoint(&group);
+                    let small = fun.len() <= self.budget || self.census.uses(&fun.name) == 1;
+
+                    if !recursive && small {
+                        let snap = snapshot(&scope, fun.free());
+                        self.funs.insert(*site, (fun.clone(), snap));
+                    }
+                }
+
+                let body = self.expr(*body, scope);
+
+                for site in sites {
+                    self.funs.remove(&site);
+                }
+
+                if funs.is_empty() {
+                    body
+                } else {
+                    Funs {
+                        funs,
+                        body: Box::new(body),
+                    }
+                }
+            }
+
+            AppC { cnt, args } => {
+                let cnt = self.resolve(&scope, cnt);
+                let site = scope.get(&cnt).copied();
+                let found = site.and_then(|site| self.cnts.remove(&site).map(|def| (site, def)));
+
+                if let Some((site, (def, snap))) = found {
+                    if visible(&scope, &snap) {
+                        if let Some(body) = self.instantiate(&def.body, &def.args, &args) {
+                            self.inlined += 1;
+                            return self.expr(body, scope);
+                        }
+                    }
+                    self.cnts.insert(site, (def, snap));
+                }
+
+                AppC { cnt, args }
+            }
+
+            AppF { fun, ret, args } => {
+                let ret = self.resolve(&scope, ret);
+                let site = scope.get(&fun).copied();
+                let found = site.and_then(|site| self.funs.get(&site).cloned());
+
+                if let Some((def, snap)) = found {
+                    if visible(&scope, &snap) {
+                        let mut formals = vec![def.ret.clone()];
+                        formals.extend(def.args.iter().cloned());
+                        let mut actuals = vec![ret.clone()];
+                        actuals.extend(args.iter().cloned());
+
+                        if let Some(body) = self.instantiate(&def.body, &formals, &actuals) {
+                            self.inlined += 1;
+                            return self.expr(body, scope);
+                        }
+                    }
+                }
+
+                AppF { fun, ret, args }
+            }
+
+            If { op, args, t, f } => {
+                let t = self.resolve(&scope, t);
+                let f = self.resolve(&scope, f);
+                If { op, args, t, f }
+            }
+            Halt(name) => Halt(name),
+        }
+    }
+}
+
+// Collects the return continuations `fun` is called with into `rets`. Fails
+// if `fun` is used as a value, or called from inside a nested function.
+fn call_rets(tree: &CpsExpr, fun: &Name, rets: &mut HashSet<Name>) -> bool {
+    use BaseCpsExpr::*;
+
+    match tree {
+        Const { body, .. } => call_rets(body, fun, rets),
+        Prim { args, body, .. } => !args.contains(fun) && call_rets(body, fun, rets),
+        Cnts { cnts, body } => {
+            cnts.iter().all(|cnt| call_rets(&cnt.body, fun, rets)) && call_rets(body, fun, rets)
+        }
+        Funs { funs, body } => {
+            funs.iter().all(|f| !f.free().contains(fun)) && call_rets(body, fun, rets)
+        }
+        AppC { cnt, args } => cnt != fun && !args.contains(fun),
+        AppF {
+            fun: callee,
+            ret,
+            args,
+        } => {
+            if callee == fun {
+                rets.insert(ret.clone());
+            }
+            ret != fun && !args.contains(fun)
+        }
+        If { args, t, f, .. } => !args.contains(fun) && t != fun && f != fun,
+        Halt(name) => name != fun,
+    }
+}
+
+// Turns every call to `fun` into a jump
+fn jumpify(tree: CpsExpr, fun: &Name) -> CpsExpr {
+    use BaseCpsExpr::*;
+
+    match tree {
+        Const { name, value, body } => Const {
+            name,
+            value,
+            body: Box::new(jumpify(*body, fun)),
+        },
+        #[rustfmt::skip]
+        Prim { name, op, args, loc, body } => Prim { name, op, args, loc, body: Box::new(jumpify(*body, fun)) },
+        Cnts { cnts, body } => Cnts {
+            cnts: cnts
+                .into_iter()
+                .map(|CntDef { name, args, body }| CntDef {
+                    name,
+                    args,
+                    body: jumpify(body, fun),
+                })
+                .collect(),
+            body: Box::new(jumpify(*body, fun)),
+        },
+        Funs { funs, body } => Funs {
+            funs,
+            body: Box::new(jumpify(*body, fun)),
+        },
+        AppF {
+            fun: callee, args, ..
+        } if &callee == fun => AppC { cnt: callee, args },
+        other => other,
+    }
+}
+
+// Adds `cnt` to the group that binds `sibling`
+fn place(tree: CpsExpr, sibling: &Name, cnt: CntDef<LitHigh>) -> CpsExpr {
+    let mut cnt = Some(cnt);
+    let tree = place_rec(tree, sibling, &mut cnt);
+    assert!(cnt.is_none(), "no binding for {}", sibling);
+    tree
+}
+
+fn place_rec(tree: CpsExpr, sibling: &Name, cnt: &mut Option<CntDef<LitHigh>>) -> CpsExpr {
+    use BaseCpsExpr::*;
+
+    if cnt.is_none() {
+        return tree;
+    }
+
+    match tree {
+        Const { name, value, body } => Const {
+            name,
+            value,
+            body: Box::new(place_rec(*body, sibling, cnt)),
+        },
+        #[rustfmt::skip]
+        Prim { name, op, args, loc, body } => Prim { name, op, args, loc, body: Box::new(place_rec(*body, sibling, cnt)) },
+        Cnts { mut cnts, body } => {
+            if cnts.iter().any(|c| &c.name == sibling) {
+                cnts.push(cnt.take().unwrap());
+                return Cnts { cnts, body };
+            }
+
+            let cnts = cnts
+                .into_iter()
+                .map(|CntDef { name, args, body }| CntDef {
+                    name,
+                    args,
+                    body: place_rec(body, sibling, cnt),
+                })
+                .collect();
+
+            Cnts {
+                cnts,
+                body: Box::new(place_rec(*body, sibling, cnt)),
+            }
+        }
+        Funs { funs, body } => Funs {
+            funs,
+            body: Box::new(place_rec(*body, sibling, cnt)),
+        },
+        other => other,
+    }
+}
+
+/// Rewrites functions that always return to the same continuation into
+/// continuations. Every call from outside the function has to pass the same
+/// return continuation, and every recursive call has to be a tail call.
+pub struct Contification;
+
+impl Contification {
+    pub fn new() -> Self {
+        Self
+    }
+
+    // The continuation every call to `fun` from `body`, its scope, returns
+    // to, and whether that continuation is bound inside `body`
+    fn target(fun: &FunDef<LitHigh>, body: &CpsExpr) -> Option<(Name, bool)> {
+        let mut inner = HashSet::new();
+        let mut outer = HashSet::new();
+
+        let body_binders = body.binders();
+        let fun_binders = fun.body.binders();
+
+        let calls_ok = call_rets(&fun.body, &fun.name, &mut inner)
+            && call_rets(body, &fun.name, &mut outer)
+            && inner.iter().all(|ret| ret == &fun.ret)
+            && !body_binders.contains(&fun.name)
+            && !fun_binders.contains(&fun.name)
+            && !fun_binders.contains(&fun.ret);
+
+        let ret = match outer.into_iter().collect::<Vec<_>>().as_slice() {
+            [ret] if calls_ok && !fun_binders.contains(ret) && ret != &fun.ret => ret.clone(),
+            _ => return None,
+        };
+
+        // if the continuation is bound inside the scope, the new continuation
+        // joins its group, as long as nothing in between shadows what it uses
+        match body_binders.iter().filter(|b| *b == &ret).count() {
+            0 => Some((ret, false)),
+            1 if body_binders.iter().all(|b| !fun.free().contains(b)) => Some((ret, true)),
+            _ => None,
+        }
+    }
+
+    fn contify(fun: FunDef<LitHigh>, body: CpsExpr, ret: Name, local: bool) -> CpsExpr {
+        let FunDef {
+            name,
+            ret: formal,
+            args,
+            body: fbody,
+        } = fun;
+
+        let cnt = CntDef {
+            name: name.clone(),
+            args,
+            body: jumpify(fbody.subst(Subst::one(formal, ret.clone())), &name),
+        };
+        let body = jumpify(body, &name);
+
+        if local {
+            place(body, &ret, cnt)
+        } else {
+            BaseCpsExpr::Cnts {
+                cnts: vec![cnt],
+                body: Box::new(body),
+            }
+        }
+    }
+}
+
+impl TreePass for Contification {
+    fn apply(self, tree: CpsExpr, stats: &mut Stats) -> CpsExpr {
+        use BaseCpsExpr::*;
+
+        match tree {
+            Const { name, value, body } => Const {
+                name,
+                value,
+                body: Box::new(Contification.apply(*body, stats)),
+            },
+
+            #[rustfmt::skip]
+            Prim { name, op, args, loc, body } => Prim { name, op, args, loc, body: Box::new(Contification.apply(*body, stats)) },
+
+            #[rustfmt::skip]
+            Cnts { cnts, body } =>
+                Cnts {
+                    cnts: cnts.into_iter().map(|cnt| {
+                        let CntDef { name, args, body } = cnt;
+                        CntDef { name, args, body: Contification.apply(body, stats) }
+                    }).collect(),
+                    body: Box::new(Contification.apply(*body, stats)),
+                },
+
+            Funs { funs, body } => {
+                let funs = funs
+                    .into_iter()
+                    .map(|fun| {
+                        let FunDef {
+                            name,
+                            args,
+                            body,
+                            ret,
+                        } = fun;
+                        FunDef {
+                            name,
+                            args,
+                            body: Contification.apply(body, stats),
+                            ret,
+                        }
+                    })
+                    .collect::<Vec<_>>();
+                let mut body = Contification.apply(*body, stats);
+
+                // functions that other members of the group call stay functions
+                let mut kept = vec![];
+                let mut rest = funs;
+                while let Some(fun) = rest.pop() {
+                    let called = kept
+                        .iter()
+                        .chain(rest.iter())
+                        .any(|other: &FunDef<LitHigh>| other.free().contains(&fun.name));
+
+                    if called {
+                        kept.push(fun);
+                        continue;
+                    }
+
+                    match Self::target(&fun, &body) {
+                        Some((ret, local)) => {
+                            stats.contified += 1;
+                            body = Self::contify(fun, body, ret, local);
+                        }
+                        None => kept.push(fun),
+                    }
+                }
+
+                if kept.is_empty() {
+                    body
+                } else {
+                    kept.reverse();
+                    Funs {
+                        funs: kept,
+                        body: Box::new(body),
+                    }
+                }
+            }
+
+            other => other,
+        }
+    }
+}
+
+// Evaluates a primitive on constants the way the program does at runtime,
+// including its runtime errors
+pub fn eval_op(op: &Name, args: &[LitHigh]) -> Result<LitHigh, &'static str> {
+    use LitHigh::*;
+    let bool = |b: bool| Int(Integer::from(b as i64));
+
+    let out = match (op.0.as_str(), args) {
+        ("id", [x]) => x.clone(),
+        ("to_float", [Int(x)]) => Float(x.to_f64()),
+        ("truncate", [Float(x)]) => Int(Integer::truncate(*x)?),
+
+        // chars are their code points
+        ("concat", [Str(x), Str(y)]) => Str(format!("{}{}", x, y)),
+        ("length", [Str(x)]) => Int(str_length(x)),
+        ("char_at", [Str(x), Int(i)]) => Int((checked_char_at(x, i)? as i64).into()),
+        ("substring", [Str(x), Int(i), Int(j)]) => Str(checked_substring(x, i, j)?),
+        ("compare", [Str(x), Str(y)]) => Int(str_compare(x, y)),
+        ("int_to_string", [Int(x)]) => Str(x.to_string()),
+        ("string_to_int", [Str(x)]) => Int(checked_string_to_int(x)?),
+
+        ("+.", [Float(x), Float(y)]) => Float(x + y),
+        ("-.", [Float(x), Float(y)]) => Float(x - y),
+        ("*.", [Float(x), Float(y)]) => Float(x * y),
+        ("/.", [Float(x), Float(y)]) => Float(x / y),
+        ("==.", [Float(x), Float(y)]) => bool(x == y),
+        ("!=.", [Float(x), Float(y)]) => bool(x != y),
+        ("<.", [Float(x), Float(y)]) => bool(x < y),
+        (">.", [Float(x), Float(y)]) => bool(x > y),
+        ("<=.", [Float(x), Float(y)]) => bool(x <= y),
+        (">=.", [Float(x), Float(y)]) => bool(x >= y),
+
+        ("+" | "-" | "*" | "/" | "%" | "shl" | "shr", [Int(x), Int(y)]) => {
+            Int(checked_arith(&op.0, x, y)?)
+        }
+        ("~", [Int(x)]) => Int(x.not()),
+        ("==", [Int(x), Int(y)]) => bool(x == y),
+        ("!=", [Int(x), Int(y)]) => bool(x != y),
+        ("<", [Int(x), Int(y)]) => bool(x < y),
+        (">", [Int(x), Int(y)]) => bool(x > y),
+        ("<=", [Int(x), Int(y)]) => bool(x <= y),
+        (">=", [Int(x), Int(y)]) => bool(x >= y),
+        ("&&", [Int(x), Int(y)]) => Int(x.and(y)),
+        ("||", [Int(x), Int(y)]) => Int(x.or(y)),
+        ("!", [Int(x)]) => bool(!x.is_positive()),
+        _ => panic!("unknown op: {} on {:?}", op, args),
     };
 
-    LitHigh::Int(out)
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{cps_eval, cps_parser, pass_manager::PassManager, verify::Verifier};
+    use BaseCpsExpr::*;
+
+    fn n(s: &str) -> Name {
+        Name::new(s)
+    }
+
+    fn int(name: &str, value: i64, body: CpsExpr) -> CpsExpr {
+        Const {
+            name: n(name),
+            value: LitHigh::Int(value.into()),
+            body: Box::new(body),
+        }
+    }
+
+    fn prim(name: &str, op: &str, args: &[&str], body: CpsExpr) -> CpsExpr {
+        Prim {
+            name: n(name),
+            op: n(op),
+            args: args.iter().map(|a| n(a)).collect(),
+            loc: None,
+            body: Box::new(body),
+        }
+    }
+
+    fn cnt(name: &str, args: &[&str], body: CpsExpr) -> CntDef<LitHigh> {
+        CntDef {
+            name: n(name),
+            args: args.iter().map(|a| n(a)).collect(),
+            body,
+        }
+    }
+
+    fn fun(name: &str, ret: &str, args: &[&str], body: CpsExpr) -> FunDef<LitHigh> {
+        FunDef {
+            name: n(name),
+            ret: n(ret),
+            args: args.iter().map(|a| n(a)).collect(),
+            body,
+        }
+    }
+
+    fn appc(cnt: &str, args: &[&str]) -> CpsExpr {
+        AppC {
+            cnt: n(cnt),
+            args: args.iter().map(|a| n(a)).collect(),
+        }
+    }
+
+    fn appf(fun: &str, ret: &str, args: &[&str]) -> CpsExpr {
+        AppF {
+            fun: n(fun),
+            ret: n(ret),
+            args: args.iter().map(|a| n(a)).collect(),
+        }
+    }
+
+    fn halt(name: &str) -> CpsExpr {
+        Halt(n(name))
+    }
+
+    // reads of a block built in scope are its descriptor and fields
+    #[test]
+    #[rustfmt::skip]
+    fn shrink_known_block() {
+        let tree = int("d", 1, int("a", 5, int("b", 7,
+            prim("blk", "data", &["d", "a", "b"],
+            prim("t", "desc", &["blk"],
+            int("i", 1,
+            prim("f", "field", &["blk", "i"],
+            prim("s", "+", &["t", "f"], halt("s")))))))));
+
+        assert_eq!(Shrinking::new().apply(tree, &mut Stats::default()), int("s", 8, halt("s")));
+    }
+
+    // unused pure primitives go, but not calls with effects
+    #[test]
+    fn shrink_dead_bindings() {
+        let tree = prim(
+            "u",
+            "==",
+            &["x", "x"],
+            prim("p", "print", &["x"], prim("w", "+", &["x", "x"], halt("w"))),
+        );
+        let expected = prim("p", "print", &["x"], prim("w", "+", &["x", "x"], halt("w")));
+
+        assert_eq!(
+            Shrinking::new().apply(tree, &mut Stats::default()),
+            expected
+        );
+    }
+
+    // equal constants are bound once, and a test on them is decided
+    #[test]
+    #[rustfmt::skip]
+    fn shrink_constants() {
+        let tree = int("a", 1, int("b", 2, int("c", 1, Cnts {
+            cnts: vec![cnt("t", &[], halt("c")), cnt("f", &[], halt("b"))],
+            body: Box::new(If { op: n("<"), args: vec![n("a"), n("b")], t: n("t"), f: n("f") }),
+        })));
+        let expected = int("a", 1, int("b", 2, Cnts {
+            cnts: vec![cnt("t", &[], halt("a")), cnt("f", &[], halt("b"))],
+            body: Box::new(AppC { cnt: n("t"), args: vec![] }),
+        }));
+
+        assert_eq!(Shrinking::new().apply(tree, &mut Stats::default()), expected);
+    }
+
+    // Both functions always return to the same place, so they become
+    // continuations: loop returns to k from inside f and joins its group,
+    // and f returns to end
+    #[test]
+    #[rustfmt::skip]
+    fn contify_single_return() {
+        let loop_body = int("z", 0, Cnts {
+            cnts: vec![
+                cnt("done", &[], appc("q", &["n"])),
+                cnt("more", &[], int("one", 1, prim("m", "-", &["n", "one"], appf("loop", "q", &["m"])))),
+            ],
+            body: Box::new(If { op: n("=="), args: vec![n("n"), n("z")], t: n("done"), f: n("more") }),
+        });
+        let k = cnt("k", &["v"], prim("w", "+", &["v", "x"], appc("r", &["w"])));
+        let tree = Funs {
+            funs: vec![fun("f", "r", &["x"], Funs {
+                funs: vec![fun("loop", "q", &["n"], loop_body)],
+                body: Box::new(Cnts { cnts: vec![k], body: Box::new(appf("loop", "k", &["x"])) }),
+            })],
+            body: Box::new(Cnts {
+                cnts: vec![cnt("end", &["e"], halt("e"))],
+                body: Box::new(int("a", 3, appf("f", "end", &["a"]))),
+            }),
+        };
+
+        let loop_cnt = cnt("loop", &["n"], int("z", 0, Cnts {
+            cnts: vec![
+                cnt("done", &[], appc("k", &["n"])),
+                cnt("more", &[], int("one", 1, prim("m", "-", &["n", "one"], appc("loop", &["m"])))),
+            ],
+            body: Box::new(If { op: n("=="), args: vec![n("n"), n("z")], t: n("done"), f: n("more") }),
+        }));
+        let expected = Cnts {
+            cnts: vec![
+                cnt("end", &["e"], halt("e")),
+                cnt("f", &["x"], Cnts {
+                    cnts: vec![
+                        cnt("k", &["v"], prim("w", "+", &["v", "x"], appc("end", &["w"]))),
+                        loop_cnt,
+                    ],
+                    body: Box::new(appc("loop", &["x"])),
+                }),
+            ],
+            body: Box::new(int("a", 3, appc("f", &["a"]))),
+        };
+
+        let mut stats = Stats::default();
+        assert_eq!(Contification::new().apply(tree, &mut stats), expected);
+        assert_eq!(stats.contified, 2);
+    }
+
+    // called with two different continuations, sq stays a function
+    #[test]
+    #[rustfmt::skip]
+    fn contify_two_returns() {
+        let tree = Funs {
+            funs: vec![fun("sq", "r", &["x"], prim("y", "*", &["x", "x"], appc("r", &["y"])))],
+            body: Box::new(Cnts {
+                cnts: vec![cnt("k", &["v"], Cnts {
+                    cnts: vec![cnt("j", &["u"], prim("s", "+", &["u", "v"], halt("s")))],
+                    body: Box::new(appf("sq", "j", &["v"])),
+                })],
+                body: Box::new(int("a", 3, appf("sq", "k", &["a"]))),
+            }),
+        };
+
+        let mut stats = Stats::default();
+        assert_eq!(Contification::new().apply(tree.clone(), &mut stats), tree);
+        assert_eq!(stats.contified, 0);
+    }
+
+    fn has_funs(tree: &CpsExpr) -> bool {
+        match tree {
+            Const { body, .. } | Prim { body, .. } => has_funs(body),
+            Cnts { cnts, body } => cnts.iter().any(|cnt| has_funs(&cnt.body)) || has_funs(body),
+            Funs { .. } => true,
+            AppC { .. } | AppF { .. } | If { .. } | Halt(_) => false,
+        }
+    }
+
+    // The source lowered to CPS, checking what the tree interpreter makes of
+    // it first
+    fn lower(source: &str, expected: &str) -> CpsExpr {
+        use crate::{
+            ast_to_cps::AstToCps, checker::TypeChecker, interp, lexer::Token, parser::Parser,
+        };
+        use logos::Logos;
+
+        let program = Parser::new(Token::lexer(source)).parse_program();
+        let program = TypeChecker::new().infer(program);
+        assert_eq!(interp::eval_prog(&program).unwrap().to_string(), expected);
+
+        let cps = AstToCps::convert(program);
+        Verifier::new().unique_binders(true).verify(&cps).unwrap();
+        cps
+    }
+
+    // a loop local to a block in a function body ends up as continuations
+    #[test]
+    fn contify_local_loop() {
+        let source = "
+            fn sum(x) = {
+                fn go(n, acc) = if n == 0 acc else go(n - 1, acc + n)
+                go(x, 0)
+            }
+            sum(10) + 1
+        ";
+        let cps = PassManager::opt_level(2).unwrap().run(lower(source, "56"));
+        assert!(!has_funs(&cps), "{:#}", cps);
+        assert_eq!(eval(&cps), "56");
+    }
+
+    // the fields of a record built in scope are known, through an update too
+    #[test]
+    fn record_fields_fold() {
+        let source = r#"
+            data Person = Person { name: String, age: Int }
+            let p = Person { name: "a", age: 41 }
+            let q = { p with name = "b" }
+            q.age + 1
+        "#;
+        let cps = PassManager::opt_level(2).unwrap().run(lower(source, "42"));
+        let expected = cps_parser::parse("const a = 42; halt(a)").unwrap();
+        assert!(cps.alpha_eq(&expected), "{:#}", cps);
+    }
+
+    fn eval(tree: &CpsExpr) -> String {
+        match cps_eval::eval(tree) {
+            Ok(value) => value.to_string(),
+            Err(err) => err.to_string(),
+        }
+    }
+
+    // Applies `pass` to the CPS in `text`, checking that what comes out is
+    // well formed and computes what went in
+    fn run(pass: impl TreePass, text: &str, stats: &mut Stats) -> CpsExpr {
+        let tree = cps_parser::parse(text).unwrap();
+        let out = pass.apply(tree.clone(), stats);
+
+        if let Err(err) = Verifier::new().unique_binders(true).verify(&out) {
+            panic!("ill-formed CPS: {}\n{:#}", err, out);
+        }
+        assert_eq!(eval(&tree), eval(&out), "in\n{}\nout\n{:#}", text, out);
+        out
+    }
+
+    // Runs a pass as `run` does, and compares the result with the expected
+    // CPS, up to the names of binders
+    fn check(pass: impl TreePass, text: &str, expected: &str) {
+        let out = run(pass, text, &mut Stats::default());
+        let expected = cps_parser::parse(expected).unwrap();
+        assert!(
+            out.alpha_eq(&expected),
+            "expected\n{:#}\ngot\n{:#}",
+            expected,
+            out
+        );
+    }
+
+    // A small function is expanded at its call. The definitions it leaves
+    // dead are for shrinking to drop, and the jump to k_1 for the next round.
+    #[test]
+    fn inline_call() {
+        check(
+            Inlining::new(16),
+            "
+            function sq_1(r_1, x_1) {
+                let y_1 = x_1 * x_1;
+                r_1(y_1)
+            }
+            cnt k_1(v_1) {
+                const b_1 = 1;
+                let w_1 = v_1 + b_1;
+                halt(w_1)
+            }
+            const a_1 = 3;
+            sq_1(k_1, a_1)
+            ",
+            "
+            function sq(r, x) {
+                let y = x * x;
+                r(y)
+            }
+            cnt k(v) {
+                const b = 1;
+                let w = v + b;
+                halt(w)
+            }
+            const a = 3;
+            let y2 = a * a;
+            k(y2)
+            ",
+        );
+    }
+
+    // A continuation reuses what its scope computed, with the operands of +
+    // in either order, and a second division can't trap. Allocations are not
+    // shared, and a nested function starts afresh.
+    #[test]
+    fn cse_shares_pure_prims() {
+        check(
+            Cse::new(),
+            r#"
+            function f(r, x, y) {
+                let a = x + y;
+                let q = div(x, y, "1:1");
+                cnt k() {
+                    let b = y + x;
+                    let t = div(x, y, "2:1");
+                    let l = data(x);
+                    let m = data(x);
+                    let d = b * t;
+                    r(d)
+                }
+                function g(s) {
+                    let e = x + y;
+                    s(e)
+                }
+                k()
+            }
+            cnt end(v) { halt(v) }
+            const one = 1;
+            const two = 2;
+            f(end, one, two)
+            "#,
+            r#"
+            function f(r, x, y) {
+                let a = x + y;
+                let q = div(x, y, "1:1");
+                cnt k() {
+                    let l = data(x);
+                    let m = data(x);
+                    let d = a * q;
+                    r(d)
+                }
+                function g(s) {
+                    let e = x + y;
+                    s(e)
+                }
+                k()
+            }
+            cnt end(v) { halt(v) }
+            const one = 1;
+            const two = 2;
+            f(end, one, two)
+            "#,
+        );
+    }
+
+    // Division by a power of two becomes a shift that rounds toward zero
+    // like `/`, so -7 / 4 stays -1
+    #[test]
+    fn simplify_strength_reduction() {
+        check(
+            Simplify::new(),
+            "
+            function f(r, x) {
+                const four = 4;
+                const eight = 8;
+                let q = x / four;
+                let p = eight * q;
+                r(p)
+            }
+            cnt k(v) { halt(v) }
+            const a = -7;
+            f(k, a)
+            ",
+            "
+            function f(r, x) {
+                const four = 4;
+                const eight = 8;
+                const c2 = 2;
+                let q = shr(x, c2);
+                const c3 = 3;
+                let p = shl(q, c3);
+                r(p)
+            }
+            cnt k(v) { halt(v) }
+            const a = -7;
+            f(k, a)
+            ",
+        );
+    }
+
+    // xorshift, enough to pick operands and operators
+    struct Random(u64);
+
+    impl Random {
+        fn below(&mut self, n: usize) -> usize {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 7;
+            self.0 ^= self.0 << 17;
+            (self.0 % n as u64) as usize
+        }
+
+        fn pick<'a, T>(&mut self, items: &'a [T]) -> &'a T {
+            &items[self.below(items.len())]
+        }
+    }
+
+    // A function of three unknown ints computing a random chain of
+    // primitives on them and on constants the rewrites look for, then
+    // branching on a random test
+    fn random_program(random: &mut Random) -> String {
+        const CONSTS: [i64; 14] = [0, 1, -1, 2, -2, 3, 4, 8, -8, 16, 1024, 7, i64::MAX, 1 << 62];
+        const ARGS: [i64; 10] = [0, 1, -1, 5, -5, -7, 9, -16, 1 << 40, -(1 << 40)];
+        const INT_OPS: [&str; 7] = ["+", "-", "*", "div", "rem", "shl", "shr"];
+        const TESTS: [&str; 6] = ["==", "!=", "<", ">", "<=", ">="];
+
+        let mut ints = vec!["x".to_string(), "y".to_string(), "z".to_string()];
+        let mut bools = vec![];
+        let mut body = String::new();
+
+        for i in 0..12 {
+            let name = format!("v{}", i);
+            let int = random.pick(&ints).clone();
+            let other = random.pick(&ints).clone();
+            match random.below(6) {
+                0 => {
+                    body += &format!("const {} = {};\n", name, random.pick(&CONSTS));
+                    ints.push(name);
+                }
+                1 | 2 => {
+                    let line = match *random.pick(&INT_OPS) {
+                        op @ ("div" | "rem" | "shl" | "shr") => {
+                            format!("let {} = {}({}, {}, \"{}:1\");\n", name, op, int, other, i)
+                        }
+                        op => format!("let {} = {} {} {};\n", name, int, op, other),
+                    };
+                    body += &line;
+                    ints.push(name);
+                }
+                3 => {
+                    let op = random.pick(&TESTS);
+                    body += &format!("let {} = {} {} {};\n", name, int, op, other);
+                    bools.push(name);
+                }
+                _ if !bools.is_empty() => {
+                    let b = random.pick(&bools).clone();
+                    let line = match random.below(4) {
+                        0 => format!("let {} = ! {};\n", name, b),
+                        1 => format!("let {} = {} && {};\n", name, b, random.pick(&bools)),
+                        2 => format!("let {} = {} || {};\n", name, b, random.pick(&bools)),
+                        _ => format!("let {} = ~ {};\n", name, int),
+                    };
+                    body += &line;
+                    if line.contains('~') {
+                        ints.push(name);
+                    } else {
+                        bools.push(name);
+                    }
+                }
+                _ => {}
+            }
+        }
+
+        // a test on a boolean compared with 0 or 1, or on two ints
+        let test = match bools.last() {
+            Some(b) if random.below(2) == 0 => {
+                let k = random.below(2);
+                body += &format!("const k = {};\n", k);
+                format!("{} {} k", b, random.pick(&["==", "!="]))
+            }
+            _ => format!(
+                "{} {} {}",
+                random.pick(&ints),
+                random.pick(&TESTS),
+                random.pick(&ints)
+            ),
+        };
+        let (t, f) = (random.pick(&ints), random.pick(&ints));
+
+        format!(
+            "
+            function f(r, x, y, z) {{
+                {body}
+                cnt t() {{ r({t}) }}
+                cnt e() {{ r({f}) }}
+                if ({test}) {{ t() }} else {{ e() }}
+            }}
+            cnt done(v) {{ halt(v) }}
+            const a = {};
+            const b = {};
+            const c = {};
+            f(done, a, b, c)
+            ",
+            random.pick(&ARGS),
+            random.pick(&ARGS),
+            random.pick(&ARGS),
+        )
+    }
+
+    // the rewrites keep what random primitives compute, runtime errors
+    // included, on negative operands as well
+    #[test]
+    fn simplify_random_prims() {
+        let mut random = Random(0x2545_f491_4f6c_dd1d);
+        let mut stats = Stats::default();
+        for _ in 0..1000 {
+            let text = random_program(&mut random);
+            run(Simplify::new(), &text, &mut stats);
+        }
+        assert!(stats.folded > 500, "only {} rewrites", stats.folded);
+    }
+
+    // a copy made by id is the block it copies, so its fields are known
+    #[test]
+    fn shrink_follows_id() {
+        check(
+            Shrinking::new(),
+            "
+            const t = 0;
+            const a = 5;
+            let d = data(t, a);
+            let p = id(d);
+            const i = 0;
+            let x = field(p, i);
+            halt(x)
+            ",
+            "
+            const a = 5;
+            halt(a)
+            ",
+        );
+    }
+
+    // what would stop the program with a runtime error is left to run,
+    // and sums fold past a machine word
+    #[test]
+    fn shrink_keeps_traps() {
+        check(
+            Shrinking::new(),
+            r#"
+            const a = 7;
+            const b = 3;
+            const z = 0;
+            const m = 9223372036854775807;
+            let r = rem(a, b, "5:1");
+            let q = div(a, z, "6:1");
+            let o = m + a;
+            let s = o + r;
+            halt(s)
+            "#,
+            r#"
+            const a = 7;
+            const z = 0;
+            let q = div(a, z, "6:1");
+            const s = 9223372036854775815;
+            halt(s)
+            "#,
+        );
+    }
+
+    // b is expanded into a before the loop over the group reaches it, and
+    // the alias after it jumps to must survive
+    #[test]
+    fn inline_into_earlier_sibling() {
+        run(
+            Inlining::new(16),
+            "
+            function f(rc, z, l) {
+                cnt after(m) { rc(m) }
+                cnt a() { b() }
+                cnt b() {
+                    const c = 1;
+                    after(c)
+                }
+                if (z == l) { a() } else { a() }
+            }
+            cnt done(r) { halt(r) }
+            const x = 2;
+            f(done, x, x)
+            ",
+            &mut Stats::default(),
+        );
+    }
 }
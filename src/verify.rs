@@ -0,0 +1,307 @@
+use std::{
+    collections::{HashMap, HashSet},
+    fmt::{self, Display},
+};
+
+use crate::{ast::Name, cps::CpsExpr};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Kind {
+    Value,
+    Fun(usize),
+    Cnt(usize),
+    // `halt` ends the program from anywhere, with or without a result
+    Halt,
+}
+
+type Env = HashMap<Name, Kind>;
+
+#[derive(Debug, Clone)]
+pub struct VerifyError {
+    pub message: String,
+    // first line of the offending node, as printed
+    pub node: String,
+}
+
+impl Display for VerifyError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}\n    at: {}", self.message, self.node)
+    }
+}
+
+/// Checks that a CPS tree is well scoped: every name is bound before it is
+/// used, continuations are only jumped to (or passed as a return continuation)
+/// and never leave the function that binds them, and every jump and known call
+/// matches the arity of its target.
+pub struct Verifier {
+    unique: bool,
+    seen: HashSet<Name>,
+}
+
+impl Verifier {
+    pub fn new() -> Self {
+        Self {
+            unique: false,
+            seen: HashSet::new(),
+        }
+    }
+
+    // Also require every name to be bound at most once in the whole tree
+    pub fn unique_binders(mut self, unique: bool) -> Self {
+        self.unique = unique;
+        self
+    }
+
+    pub fn verify<Lit: Display>(mut self, tree: &CpsExpr<Lit>) -> Result<(), VerifyError> {
+        let env = HashMap::from([(Name::new("halt"), Kind::Halt)]);
+        self.expr(tree, env)
+    }
+
+    fn error<Lit: Display>(&self, tree: &CpsExpr<Lit>, message: String) -> VerifyError {
+        let node = tree.to_string();
+        let node = node.lines().next().unwrap_or("").to_string();
+        VerifyError { message, node }
+    }
+
+    fn bind<Lit: Display>(
+        &mut self,
+        tree: &CpsExpr<Lit>,
+        env: &mut Env,
+        name: &Name,
+        kind: Kind,
+    ) -> Result<(), VerifyError> {
+        if self.unique && !self.seen.insert(name.clone()) {
+            return Err(self.error(tree, format!("{} is bound more than once", name)));
+        }
+
+        env.insert(name.clone(), kind);
+        Ok(())
+    }
+
+    fn value<Lit: Display>(
+        &self,
+        tree: &CpsExpr<Lit>,
+        env: &Env,
+        name: &Name,
+    ) -> Result<(), VerifyError> {
+        match env.get(name) {
+            Some(Kind::Value) | Some(Kind::Fun(_)) => Ok(()),
+            Some(Kind::Cnt(_)) | Some(Kind::Halt) => {
+                Err(self.error(tree, format!("continuation {} used as a value", name)))
+            }
+            None => Err(self.error(tree, format!("unbound name {}", name))),
+        }
+    }
+
+    fn cnt<Lit: Display>(
+        &self,
+        tree: &CpsExpr<Lit>,
+        env: &Env,
+        name: &Name,
+        arity: usize,
+    ) -> Result<(), VerifyError> {
+        match env.get(name) {
+            Some(Kind::Cnt(n)) if *n == arity => Ok(()),
+            Some(Kind::Halt) => Ok(()),
+            Some(Kind::Cnt(n)) => Err(self.error(
+                tree,
+                format!(
+                    "continuation {} takes {} argument(s), given {}",
+                    name, n, arity
+                ),
+            )),
+            Some(_) => Err(self.error(tree, format!("{} is not a continuation", name))),
+            None => Err(self.error(tree, format!("unbound continuation {}", name))),
+        }
+    }
+
+    fn expr<Lit: Display>(&mut self, tree: &CpsExpr<Lit>, mut env: Env) -> Result<(), VerifyError> {
+        use CpsExpr::*;
+
+        match tree {
+            Const { name, body, .. } => {
+                self.bind(tree, &mut env, name, Kind::Value)?;
+                self.expr(body, env)
+            }
+            Prim {
+                name, args, body, ..
+            } => {
+                for arg in args {
+                    self.value(tree, &env, arg)?;
+                }
+                self.bind(tree, &mut env, name, Kind::Value)?;
+                self.expr(body, env)
+            }
+            Cnts { cnts, body } => {
+                for cnt in cnts {
+                    self.bind(tree, &mut env, &cnt.name, Kind::Cnt(cnt.args.len()))?;
+                }
+
+                for cnt in cnts {
+                    let mut cenv = env.clone();
+                    for arg in &cnt.args {
+                        self.bind(tree, &mut cenv, arg, Kind::Value)?;
+                    }
+                    self.expr(&cnt.body, cenv)?;
+                }
+
+                self.expr(body, env)
+            }
+            Funs { funs, body } => {
+                for fun in funs {
+                    self.bind(tree, &mut env, &fun.name, Kind::Fun(fun.args.len()))?;
+                }
+
+                for fun in funs {
+                    // continuations of the enclosing function are out of reach
+                    let mut fenv = env
+                        .iter()
+                        .filter(|(_, kind)| !matches!(kind, Kind::Cnt(_)))
+                        .map(|(name, kind)| (name.clone(), *kind))
+                        .collect();
+
+                    self.bind(tree, &mut fenv, &fun.ret, Kind::Cnt(1))?;
+                    for arg in &fun.args {
+                        self.bind(tree, &mut fenv, arg, Kind::Value)?;
+                    }
+                    self.expr(&fun.body, fenv)?;
+                }
+
+                self.expr(body, env)
+            }
+            AppC { cnt, args } => {
+                self.cnt(tree, &env, cnt, args.len())?;
+                args.iter().try_for_each(|arg| self.value(tree, &env, arg))
+            }
+            AppF { fun, ret, args } => {
+                match env.get(fun) {
+                    Some(Kind::Fun(n)) if *n != args.len() => {
+                        return Err(self.error(
+                            tree,
+                            format!(
+                                "function {} takes {} argument(s), given {}",
+                                fun,
+                                n,
+                                args.len()
+                            ),
+                        ))
+                    }
+                    _ => self.value(tree, &env, fun)?,
+                }
+
+                self.cnt(tree, &env, ret, 1)?;
+                args.iter().try_for_each(|arg| self.value(tree, &env, arg))
+            }
+            If { args, t, f, .. } => {
+                args.iter()
+                    .try_for_each(|arg| self.value(tree, &env, arg))?;
+                self.cnt(tree, &env, t, 0)?;
+                self.cnt(tree, &env, f, 0)
+            }
+            Halt(name) => self.value(tree, &env, name),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cps_parser;
+
+    fn error(text: &str, unique: bool) -> (String, String) {
+        let tree = cps_parser::parse(text).unwrap();
+        let err = Verifier::new()
+            .unique_binders(unique)
+            .verify(&tree)
+            .unwrap_err();
+        (err.message, err.node)
+    }
+
+    fn fails(text: &str, message: &str, node: &str) {
+        assert_eq!(error(text, false), (message.to_string(), node.to_string()));
+    }
+
+    #[test]
+    fn well_formed() {
+        let tree = cps_parser::parse(
+            "
+            cnt k(v) { halt(v) }
+            function f(r, x) { r(x) }
+            const a = 1;
+            f(k, a)
+            ",
+        )
+        .unwrap();
+        assert!(Verifier::new().unique_binders(true).verify(&tree).is_ok());
+    }
+
+    #[test]
+    fn unbound_name() {
+        fails(
+            "const a = 1; let s = a + b; halt(s)",
+            "unbound name b",
+            "let s = add(a, b);",
+        );
+    }
+
+    #[test]
+    fn continuation_as_value() {
+        fails(
+            "cnt k(v) { halt(v) } halt(k)",
+            "continuation k used as a value",
+            "halt(k)",
+        );
+        fails(
+            "cnt k(v) { halt(v) } const d = 0; let b = data(d, k); halt(b)",
+            "continuation k used as a value",
+            "let b = data(d, k);",
+        );
+    }
+
+    #[test]
+    fn arity_mismatch() {
+        fails(
+            "cnt k(v) { halt(v) } k()",
+            "continuation k takes 1 argument(s), given 0",
+            "k()",
+        );
+        fails(
+            "cnt k(v) { halt(v) } function f(r, x) { r(x) } f(k)",
+            "function f takes 1 argument(s), given 0",
+            "f(k, )",
+        );
+    }
+
+    // k belongs to the top level, so the body of f can't jump to it. A call
+    // reads as a jump only to a continuation in scope, so the jump is an if.
+    #[test]
+    fn outer_continuation_in_function() {
+        fails(
+            "
+            const a = 1;
+            cnt k() { halt(a) }
+            function f(r, x) {
+                cnt l() { r(x) }
+                if (x < x) { k() } else { l() }
+            }
+            k()
+            ",
+            "unbound continuation k",
+            "if (x < x) { k() } else { l() }",
+        );
+    }
+
+    #[test]
+    fn bound_twice() {
+        let text = "const a = 1; const a = 2; halt(a)";
+        let tree = cps_parser::parse(text).unwrap();
+        assert!(Verifier::new().verify(&tree).is_ok());
+        assert_eq!(
+            error(text, true),
+            (
+                "a is bound more than once".to_string(),
+                "const a = 2;".to_string()
+            )
+        );
+    }
+}